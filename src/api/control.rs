@@ -0,0 +1,138 @@
+use axum::extract::{ConnectInfo, Path, Request};
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::{extract::State, http::StatusCode, Json};
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use super::{ClientAllowlist, ControlState, HeldMessage, InterceptEdit, RewriteRule};
+use crate::proxy::intercept::{Edit, InterceptRule, Verdict};
+use crate::proxy::rewrite;
+
+// Clients outside the allowlist are refused like on the proxy listeners
+pub async fn require_allowed_client(
+    State(state): State<Arc<ControlState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    if !state.allowlist().allows(client.ip()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
+
+pub async fn get_held_messages(
+    State(state): State<Arc<ControlState>>,
+) -> (StatusCode, Json<Vec<HeldMessage>>) {
+    let held = state.intercept().held();
+    (
+        StatusCode::OK,
+        Json(held.into_iter().map(HeldMessage::from).collect()),
+    )
+}
+
+pub async fn get_held_message(
+    State(state): State<Arc<ControlState>>,
+    Path(id): Path<u64>,
+) -> (StatusCode, Json<Option<HeldMessage>>) {
+    match state.intercept().get(id) {
+        Some(message) => (StatusCode::OK, Json(Some(HeldMessage::from(message)))),
+        None => (StatusCode::NOT_FOUND, Json(None)),
+    }
+}
+
+// The body is an optional InterceptEdit, an empty one forwards the message unchanged
+pub async fn forward_held_message(
+    State(state): State<Arc<ControlState>>,
+    Path(id): Path<u64>,
+    body: Bytes,
+) -> axum::response::Response {
+    let edit = if body.iter().all(u8::is_ascii_whitespace) {
+        None
+    } else {
+        let edit = serde_json::from_slice::<InterceptEdit>(&body)
+            .map_err(|e| e.to_string())
+            .and_then(Edit::try_from);
+        match edit {
+            Ok(edit) => Some(Box::new(edit)),
+            Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        }
+    };
+    resolve(&state, id, Verdict::Forward(edit))
+}
+
+pub async fn drop_held_message(
+    State(state): State<Arc<ControlState>>,
+    Path(id): Path<u64>,
+) -> axum::response::Response {
+    resolve(&state, id, Verdict::Drop)
+}
+
+pub async fn get_intercept_rules(
+    State(state): State<Arc<ControlState>>,
+) -> (StatusCode, Json<Vec<String>>) {
+    let rules = state.intercept().rules();
+    (
+        StatusCode::OK,
+        Json(rules.iter().map(InterceptRule::to_string).collect()),
+    )
+}
+
+pub async fn set_intercept_rules(
+    State(state): State<Arc<ControlState>>,
+    Json(rules): Json<Vec<String>>,
+) -> axum::response::Response {
+    match rules.iter().map(|rule| rule.parse()).collect() {
+        Ok(rules) => {
+            state.intercept().set_rules(rules);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+pub async fn get_rewrite_rules(
+    State(state): State<Arc<ControlState>>,
+) -> (StatusCode, Json<Vec<RewriteRule>>) {
+    let rules = state.rewriter().rules();
+    (
+        StatusCode::OK,
+        Json(rules.iter().map(RewriteRule::from).collect()),
+    )
+}
+
+pub async fn set_rewrite_rules(
+    State(state): State<Arc<ControlState>>,
+    Json(rules): Json<Vec<RewriteRule>>,
+) -> axum::response::Response {
+    match rules
+        .into_iter()
+        .map(rewrite::RewriteRule::try_from)
+        .collect()
+    {
+        Ok(rules) => {
+            state.rewriter().set_rules(rules);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+pub async fn get_allowlist(
+    State(state): State<Arc<ControlState>>,
+) -> (StatusCode, Json<ClientAllowlist>) {
+    let allowlist = state.allowlist();
+    (
+        StatusCode::OK,
+        Json(ClientAllowlist::from(allowlist.as_ref())),
+    )
+}
+
+fn resolve(state: &ControlState, id: u64, verdict: Verdict) -> axum::response::Response {
+    match state.intercept().resolve(id, verdict) {
+        true => StatusCode::NO_CONTENT.into_response(),
+        false => StatusCode::NOT_FOUND.into_response(),
+    }
+}
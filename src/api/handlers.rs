@@ -1,7 +1,7 @@
 use crate::storage::storage::ReqrespStorage;
 use axum::response::IntoResponse;
 
-use super::{AppState, Reqresp};
+use super::{AppState, Reqresp, WebSocketFrame};
 use crate::scanner::Scanner;
 use axum::extract::Path;
 use axum::{extract::State, http::StatusCode, Json};
@@ -11,7 +11,7 @@ pub async fn get_reqresps_list(
     State(state): State<Arc<AppState>>,
 ) -> (StatusCode, Json<Vec<Reqresp>>) {
     let reqresps = state.db().get_reqresps().await.unwrap();
-    return (StatusCode::OK, Json(reqresps));
+    (StatusCode::OK, Json(reqresps))
 }
 
 pub async fn get_reqresp_by_id(
@@ -19,11 +19,21 @@ pub async fn get_reqresp_by_id(
     Path(reqresp_id): Path<String>,
 ) -> (StatusCode, Json<Option<Reqresp>>) {
     let reqresp = state.db().get_reqresp_by_id(&reqresp_id).await.unwrap();
-    let result = match reqresp {
+
+    match reqresp {
         Some(reqresp) => (StatusCode::OK, Json(Some(reqresp))),
         None => (StatusCode::NOT_FOUND, Json(None)),
-    };
-    result
+    }
+}
+
+pub async fn get_websocket_frames(
+    State(state): State<Arc<AppState>>,
+    Path(reqresp_id): Path<String>,
+) -> (StatusCode, Json<Vec<WebSocketFrame>>) {
+    match state.db().get_websocket_frames(&reqresp_id).await {
+        Ok(frames) => (StatusCode::OK, Json(frames)),
+        Err(_) => (StatusCode::NOT_FOUND, Json(Vec::new())),
+    }
 }
 
 pub async fn resend_request(
@@ -1,5 +1,9 @@
+pub mod control;
 pub mod handlers;
 
+use crate::proxy::allowlist;
+use crate::proxy::intercept::InterceptQueue;
+use crate::proxy::rewrite::Rewriter;
 use crate::scanner::SimpleScanner;
 use crate::storage::mongodb_storage::MongoDbStorage;
 use std::sync::Arc;
@@ -24,3 +28,36 @@ impl AppState {
         AppState { db, scanner }
     }
 }
+
+// State of the control API served by the proxy process itself
+pub struct ControlState {
+    intercept: Arc<InterceptQueue>,
+    rewriter: Arc<Rewriter>,
+    allowlist: Arc<allowlist::ClientAllowlist>,
+}
+
+impl ControlState {
+    pub fn intercept(&self) -> Arc<InterceptQueue> {
+        self.intercept.clone()
+    }
+
+    pub fn rewriter(&self) -> Arc<Rewriter> {
+        self.rewriter.clone()
+    }
+
+    pub fn allowlist(&self) -> Arc<allowlist::ClientAllowlist> {
+        self.allowlist.clone()
+    }
+
+    pub fn new(
+        intercept: Arc<InterceptQueue>,
+        rewriter: Arc<Rewriter>,
+        allowlist: Arc<allowlist::ClientAllowlist>,
+    ) -> Self {
+        ControlState {
+            intercept,
+            rewriter,
+            allowlist,
+        }
+    }
+}
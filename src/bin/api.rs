@@ -2,9 +2,13 @@ use axum::{routing::get, Router};
 
 use dotenv::dotenv;
 use log::{info, LevelFilter};
-use rusty_proxy::api::handlers::{get_reqresp_by_id, get_reqresps_list, resend_request, scan_xss};
+use rusty_proxy::api::handlers::{
+    get_reqresp_by_id, get_reqresps_list, get_websocket_frames, resend_request, scan_xss,
+};
 use rusty_proxy::api::AppState;
 use rusty_proxy::config::Config;
+use rusty_proxy::proxy::client::Client;
+use rusty_proxy::proxy::upstream_tls::UpstreamTls;
 use rusty_proxy::scanner::SimpleScanner;
 use rusty_proxy::storage::mongodb_storage::MongoDbStorage;
 use simplelog::SimpleLogger;
@@ -16,16 +20,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     dotenv().ok();
 
     let config = Config::from_env()?;
+    // Both ring (via mongodb) and aws-lc-rs are enabled, so rustls can not pick one itself
+    rustls::crypto::aws_lc_rs::default_provider()
+        .install_default()
+        .expect("crypto provider is installed only once");
 
     let client = mongodb::Client::with_uri_str(config.mongodb_uri()).await?;
     let db = Arc::new(MongoDbStorage::new(client));
-    let scanner = SimpleScanner {};
+    let client = Client::new(
+        UpstreamTls::new(config.upstream_tls())?,
+        config.client().clone(),
+    );
+    let scanner = SimpleScanner::new(client);
     let app_state = Arc::new(AppState::new(db, scanner));
 
     SimpleLogger::init(LevelFilter::Debug, simplelog::Config::default()).unwrap();
     let app = Router::new()
         .route("/requests", get(get_reqresps_list))
         .route("/requests/{reqresp_id}", get(get_reqresp_by_id))
+        .route("/requests/{reqresp_id}/frames", get(get_websocket_frames))
         .route("/repeat/{reqresp_id}", get(resend_request))
         .route("/scan/{reqresp_id}", get(scan_xss))
         .with_state(app_state);
@@ -1,9 +1,24 @@
+use axum::routing::{get, post};
+use axum::{middleware, Router};
 use dotenv::dotenv;
-use log::{error, info};
-use rusty_proxy::dto::{Reqresp, Request, Response};
-use rusty_proxy::proxy::Proxy;
+use log::{error, info, warn};
+use rusty_proxy::api::control::{
+    drop_held_message, forward_held_message, get_allowlist, get_held_message, get_held_messages,
+    get_intercept_rules, get_rewrite_rules, require_allowed_client, set_intercept_rules,
+    set_rewrite_rules,
+};
+use rusty_proxy::api::ControlState;
+use rusty_proxy::ca::CertificateAuthority;
+use rusty_proxy::dto::{Reqresp, Request, Response, WebSocketFrame};
+use rusty_proxy::proxy::allowlist::ClientAllowlist;
+use rusty_proxy::proxy::intercept::InterceptQueue;
+use rusty_proxy::proxy::rewrite::Rewriter;
+use rusty_proxy::proxy::shutdown::{self, Shutdown};
+use rusty_proxy::proxy::websocket::Frame;
+use rusty_proxy::proxy::{CaptureInfo, Proxy};
 use rusty_proxy::storage::storage::ReqrespStorage;
 use simplelog::{Config, LevelFilter, SimpleLogger};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
 use rusty_proxy::dto::hyper::{HyperRequest, HyperResponse};
@@ -13,37 +28,172 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     dotenv().ok();
 
     SimpleLogger::init(LevelFilter::Info, Config::default()).unwrap();
+    // Both ring (via mongodb) and aws-lc-rs are enabled, so rustls can not pick one itself
+    rustls::crypto::aws_lc_rs::default_provider()
+        .install_default()
+        .expect("crypto provider is installed only once");
     let config = rusty_proxy::config::Config::from_env()?;
 
     info!("Conneting to storage...");
     let mongo_client = mongodb::Client::with_uri_str(config.mongodb_uri()).await?;
     let mongo_storage = rusty_proxy::storage::mongodb_storage::MongoDbStorage::new(mongo_client);
 
-    let callback = Arc::new(Mutex::new(move |req: HyperRequest, resp: HyperResponse| {
-        let mongo_storage = mongo_storage.clone();
-        tokio::spawn(save_reqresp_to_storage(req, resp, mongo_storage));
+    // Storage writes are spawned through the shutdown handle, so they are not lost on exit
+    let shutdown = Shutdown::new();
+    let frames_storage = mongo_storage.clone();
+    let callback_shutdown = shutdown.clone();
+    let capture_limit = config.capture_limit();
+    let callback = Arc::new(Mutex::new(
+        move |req: HyperRequest, resp: HyperResponse, info: CaptureInfo| {
+            let mongo_storage = mongo_storage.clone();
+            callback_shutdown.spawn(save_reqresp_to_storage(
+                req,
+                resp,
+                info,
+                capture_limit,
+                mongo_storage,
+            ));
+        },
+    ));
+    let frame_shutdown = shutdown.clone();
+    let frame_callback = Arc::new(Mutex::new(move |frame: Frame| {
+        let frames_storage = frames_storage.clone();
+        frame_shutdown.spawn(save_frame_to_storage(frame, frames_storage));
     }));
 
+    let signal_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        match shutdown::signal().await {
+            Ok(()) => {
+                info!("Got termination signal");
+                signal_shutdown.trigger();
+            }
+            Err(e) => error!("failed to listen for termination signals: {:?}", e),
+        }
+    });
+
+    info!("Loading certificate authority...");
+    let ca = CertificateAuthority::load(config.ssl_certificate(), config.ssl_key())?;
+
+    let intercept = Arc::new(InterceptQueue::new(config.intercept().clone()));
+    let rewriter = Arc::new(Rewriter::new(config.rewrite_rules().clone()));
+    let allowlist = Arc::new(ClientAllowlist::new(config.allowed_clients().clone()));
+    match config.control_port() {
+        Some(port) => {
+            let addr = SocketAddr::new(config.control_host(), port);
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            let state = Arc::new(ControlState::new(
+                intercept.clone(),
+                rewriter.clone(),
+                allowlist.clone(),
+            ));
+            let app = Router::new()
+                .route("/intercept", get(get_held_messages))
+                .route(
+                    "/intercept/rules",
+                    get(get_intercept_rules).put(set_intercept_rules),
+                )
+                .route("/intercept/{id}", get(get_held_message))
+                .route("/intercept/{id}/forward", post(forward_held_message))
+                .route("/intercept/{id}/drop", post(drop_held_message))
+                .route(
+                    "/rewrite/rules",
+                    get(get_rewrite_rules).put(set_rewrite_rules),
+                )
+                .route("/allowlist", get(get_allowlist))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_allowed_client,
+                ))
+                .with_state(state)
+                .into_make_service_with_connect_info::<SocketAddr>();
+            info!("Control api listening on {addr}");
+            let control_shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let stopped = async move { control_shutdown.triggered().await };
+                if let Err(e) = axum::serve(listener, app)
+                    .with_graceful_shutdown(stopped)
+                    .await
+                {
+                    error!("control api failed: {:?}", e);
+                }
+            });
+        }
+        None if !config.intercept().rules.is_empty() => {
+            warn!("Breakpoints are set without the control api, held messages wait for the timeout")
+        }
+        None => {}
+    }
+
     info!("Initializing proxy...");
-    let proxy = Proxy::builder()
+    let mut proxy = Proxy::builder()
         .with_host(config.proxy_host().clone())
         .with_port(config.proxy_port())
-        .with_tls(config.ssl_certificate().clone(), config.ssl_key().clone())
+        .with_tls(ca)
+        .with_upstream_tls(config.upstream_tls().clone())
+        .with_client(config.client().clone())
+        .with_capture_limit(config.capture_limit())
+        .with_capture_scope(config.capture_scope().clone())
+        .with_passthrough(config.passthrough().clone())
+        .with_reverse(config.reverse().clone())
+        .with_intercept(intercept)
+        .with_rewriter(rewriter)
+        .with_allowlist(allowlist)
         .with_callback(callback)
-        .build()?;
+        .with_frame_callback(frame_callback)
+        .with_shutdown(shutdown)
+        .with_drain_timeout(config.drain_timeout())
+        .with_timeouts(config.timeouts());
+    if let Some(port) = config.socks_port() {
+        proxy = proxy.with_socks_port(port);
+    }
+    if let Some(auth) = config.auth() {
+        proxy = proxy.with_auth(auth.clone());
+    }
+    if let Some(credentials) = config.socks_credentials() {
+        proxy = proxy.with_socks_credentials(credentials.clone());
+    }
+    if let Some(port) = config.transparent_port() {
+        proxy = proxy.with_transparent_port(port);
+    }
+    if let Some(port) = config.reverse_port() {
+        proxy = proxy.with_reverse_port(port);
+    }
 
-    proxy.serve().await
+    proxy.build()?.serve().await
 }
 
-async fn save_reqresp_to_storage<T>(req: HyperRequest, resp: HyperResponse, storage: T)
-where
+async fn save_reqresp_to_storage<T>(
+    req: HyperRequest,
+    resp: HyperResponse,
+    info: CaptureInfo,
+    capture_limit: usize,
+    storage: T,
+) where
     T: ReqrespStorage,
 {
     let req = Request::from(req.clone());
-    let resp = Response::from(resp.clone());
-    let reqresp = Reqresp::new(req, resp);
+    let resp = Response::decoded(resp.clone(), capture_limit);
+    let truncated = info.truncated || resp.truncated();
+    let mut reqresp = Reqresp::new(req, resp);
+    reqresp.id = info.id;
+    reqresp.truncated = truncated;
+    reqresp.user = info.user;
+    reqresp.error = info.error;
 
     if let Err(e) = storage.add_reqresp(reqresp).await {
         error!("failed to write to storage: {:?}", e);
     }
 }
+
+async fn save_frame_to_storage<T>(frame: Frame, storage: T)
+where
+    T: ReqrespStorage,
+{
+    if let Err(e) = storage
+        .add_websocket_frame(WebSocketFrame::from(frame))
+        .await
+    {
+        error!("failed to write frame to storage: {:?}", e);
+    }
+}
@@ -0,0 +1,127 @@
+use dotenv::dotenv;
+use rusty_proxy::ca::{CertificateAuthority, ExportFormat, DEFAULT_COMMON_NAME};
+use rusty_proxy::config::Config;
+use std::path::Path;
+
+const USAGE: &str = "usage:
+    rusty_proxy ca init [--force] [--cn NAME]
+    rusty_proxy ca export <pem|der|p12> <OUTPUT> [--password PASSWORD]
+    rusty_proxy ca rotate [--cn NAME]
+
+options:
+    --cert PATH    root certificate path (default: $RUSTY_PROXY_SSL_CERTIFICATE)
+    --key PATH     root private key path (default: $RUSTY_PROXY_SSL_PRIVATE_KEY)";
+
+// Command line options shared by all ca subcommands
+struct CaOptions {
+    positional: Vec<String>,
+    cert: Option<String>,
+    key: Option<String>,
+    common_name: String,
+    password: String,
+    force: bool,
+}
+
+impl CaOptions {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = CaOptions {
+            positional: Vec::new(),
+            cert: None,
+            key: None,
+            common_name: DEFAULT_COMMON_NAME.to_string(),
+            password: String::new(),
+            force: false,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("missing value for {}", name))
+            };
+            match arg.as_str() {
+                "--cert" => options.cert = Some(value("--cert")?),
+                "--key" => options.key = Some(value("--key")?),
+                "--cn" => options.common_name = value("--cn")?,
+                "--password" => options.password = value("--password")?,
+                "--force" => options.force = true,
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                _ => options.positional.push(arg),
+            }
+        }
+        Ok(options)
+    }
+
+    fn paths(&self) -> Result<(String, String), Box<dyn std::error::Error>> {
+        if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+            return Ok((cert.clone(), key.clone()));
+        }
+        let (cert, key) = Config::ca_paths_from_env()?;
+        Ok((
+            self.cert.clone().unwrap_or(cert),
+            self.key.clone().unwrap_or(key),
+        ))
+    }
+}
+
+fn main() {
+    dotenv().ok();
+    let mut args = std::env::args().skip(1);
+    let result = match args.next().as_deref() {
+        Some("ca") => run_ca(args),
+        _ => Err(USAGE.into()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run_ca(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn std::error::Error>> {
+    let command = args.next().ok_or(USAGE)?;
+    let options = CaOptions::parse(args)?;
+    let (cert_path, key_path) = options.paths()?;
+
+    match command.as_str() {
+        "init" => {
+            if !options.force && (Path::new(&cert_path).exists() || Path::new(&key_path).exists()) {
+                return Err(format!(
+                    "{} or {} already exists, use --force to overwrite",
+                    cert_path, key_path
+                )
+                .into());
+            }
+            let ca = CertificateAuthority::generate(&options.common_name)?;
+            ca.save(&cert_path, &key_path)?;
+            println!("Generated root certificate {}", cert_path);
+        }
+        "export" => {
+            let [format, output] = options.positional.as_slice() else {
+                return Err(USAGE.into());
+            };
+            let format: ExportFormat = format.parse()?;
+            let ca = CertificateAuthority::load(&cert_path, &key_path)?;
+            std::fs::write(output, ca.export(format, &options.password)?)?;
+            println!("Exported root certificate to {}", output);
+        }
+        "rotate" => {
+            // The new root is written next to the old one first, so a failure leaves it in place
+            let ca = CertificateAuthority::generate(&options.common_name)?;
+            let (new_cert, new_key) = (format!("{}.new", cert_path), format!("{}.new", key_path));
+            ca.save(&new_cert, &new_key)?;
+            // Previous root is kept around to be able to remove it from trust stores.
+            // It is copied, so the configured paths are only ever replaced by renames
+            for path in [&cert_path, &key_path] {
+                if Path::new(path).exists() {
+                    std::fs::copy(path, format!("{}.old", path))?;
+                }
+            }
+            std::fs::rename(&new_cert, &cert_path)?;
+            std::fs::rename(&new_key, &key_path)?;
+            println!(
+                "Rotated root certificate {}, the previous one is saved as {}.old",
+                cert_path, cert_path
+            );
+        }
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
@@ -0,0 +1,296 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use p12_keystore::{Certificate, KeyStore, KeyStoreEntry};
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::sign::CertifiedKey;
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+
+pub const DEFAULT_COMMON_NAME: &str = "Rusty Proxy Root CA";
+
+// Leaf certificates are valid for a bit less than the 398 days accepted by browsers
+const LEAF_VALIDITY_DAYS: i64 = 365;
+const ROOT_VALIDITY_DAYS: i64 = 3650;
+const PKCS12_ALIAS: &str = "rusty_proxy";
+
+// Root certificate authority used to sign per-host leaf certificates
+pub struct CertificateAuthority {
+    issuer: Issuer<'static, KeyPair>,
+    cert: CertificateDer<'static>,
+    cert_pem: String,
+}
+
+impl CertificateAuthority {
+    // Generate a brand new self-signed root certificate
+    pub fn generate(common_name: &str) -> Result<Self, CaError> {
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + Duration::days(ROOT_VALIDITY_DAYS);
+
+        let key = KeyPair::generate().map_err(CaError::Generation)?;
+        let cert = params.self_signed(&key).map_err(CaError::Generation)?;
+        Ok(CertificateAuthority {
+            cert_pem: cert.pem(),
+            cert: cert.der().clone(),
+            issuer: Issuer::new(params, key),
+        })
+    }
+
+    pub fn from_pem(cert_pem: &str, key_pem: &str) -> Result<Self, CaError> {
+        let key = KeyPair::from_pem(key_pem).map_err(CaError::InvalidKey)?;
+        let cert = CertificateDer::from_pem_slice(cert_pem.as_bytes())
+            .map_err(|_| CaError::InvalidCertificate)?;
+        let issuer = Issuer::from_ca_cert_pem(cert_pem, key).map_err(CaError::InvalidIssuer)?;
+        Ok(CertificateAuthority {
+            issuer,
+            cert,
+            cert_pem: cert_pem.to_string(),
+        })
+    }
+
+    pub fn load(cert_path: &str, key_path: &str) -> Result<Self, CaError> {
+        let cert_pem = fs::read_to_string(cert_path)?;
+        let key_pem = fs::read_to_string(key_path)?;
+        CertificateAuthority::from_pem(&cert_pem, &key_pem)
+    }
+
+    pub fn save(&self, cert_path: &str, key_path: &str) -> Result<(), CaError> {
+        for path in [cert_path, key_path] {
+            if let Some(dir) = Path::new(path).parent() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        fs::write(cert_path, &self.cert_pem)?;
+        write_private(key_path, self.key_pem().as_bytes())?;
+        Ok(())
+    }
+
+    pub fn cert(&self) -> &CertificateDer<'static> {
+        &self.cert
+    }
+
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    pub fn key_pem(&self) -> String {
+        self.issuer.key().serialize_pem()
+    }
+
+    // Serialize the root certificate (without the key) for importing into browsers
+    pub fn export(&self, format: ExportFormat, password: &str) -> Result<Vec<u8>, CaError> {
+        match format {
+            ExportFormat::Pem => Ok(self.cert_pem.clone().into_bytes()),
+            ExportFormat::Der => Ok(self.cert.to_vec()),
+            ExportFormat::Pkcs12 => {
+                let cert = Certificate::from_der(&self.cert).map_err(CaError::Pkcs12)?;
+                let mut store = KeyStore::new();
+                store.add_entry(PKCS12_ALIAS, KeyStoreEntry::Certificate(cert));
+                store.writer(password).write().map_err(CaError::Pkcs12)
+            }
+        }
+    }
+
+    // Issue a fresh leaf certificate for the given host (dns name or ip)
+    pub fn issue(&self, host: &str, provider: &CryptoProvider) -> Result<CertifiedKey, CaError> {
+        let mut params =
+            CertificateParams::new(vec![host.to_string()]).map_err(CaError::Generation)?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        params.use_authority_key_identifier_extension = true;
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + Duration::days(LEAF_VALIDITY_DAYS);
+
+        let key = KeyPair::generate().map_err(CaError::Generation)?;
+        let cert = params
+            .signed_by(&key, &self.issuer)
+            .map_err(CaError::Generation)?;
+
+        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        let signing_key = provider.key_provider.load_private_key(key_der)?;
+        Ok(CertifiedKey::new(
+            vec![cert.der().clone(), self.cert.clone()],
+            signing_key,
+        ))
+    }
+}
+
+// Key files are readable by the owner only
+fn write_private(path: &str, content: &[u8]) -> Result<(), std::io::Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(content)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Pem,
+    Der,
+    Pkcs12,
+}
+
+impl FromStr for ExportFormat {
+    type Err = CaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pem" | "crt" => Ok(ExportFormat::Pem),
+            "der" | "cer" => Ok(ExportFormat::Der),
+            "p12" | "pfx" | "pkcs12" => Ok(ExportFormat::Pkcs12),
+            _ => Err(CaError::UnknownFormat(s.to_string())),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CaError {
+    #[error("certificate authority io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid ca certificate")]
+    InvalidCertificate,
+
+    #[error("invalid ca private key: {0}")]
+    InvalidKey(rcgen::Error),
+
+    #[error("ca certificate can not be used as issuer: {0}")]
+    InvalidIssuer(rcgen::Error),
+
+    #[error("failed to generate certificate: {0}")]
+    Generation(rcgen::Error),
+
+    #[error("generated key is rejected by tls backend: {0}")]
+    Tls(#[from] rustls::Error),
+
+    #[error("failed to build pkcs12 archive: {0}")]
+    Pkcs12(p12_keystore::error::Error),
+
+    #[error("unknown export format {0:?}")]
+    UnknownFormat(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_parser::extensions::GeneralName;
+    use x509_parser::prelude::{FromDer, X509Certificate};
+
+    fn provider() -> CryptoProvider {
+        rustls::crypto::aws_lc_rs::default_provider()
+    }
+
+    #[test]
+    fn pem_round_trip() {
+        let ca = CertificateAuthority::generate("Test Root").unwrap();
+        let loaded = CertificateAuthority::from_pem(ca.cert_pem(), &ca.key_pem()).unwrap();
+        assert_eq!(loaded.cert(), ca.cert());
+        assert_eq!(loaded.key_pem(), ca.key_pem());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join(format!("rusty_proxy_ca_{}", std::process::id()));
+        let cert_path = dir.join("rootCA.crt").to_string_lossy().into_owned();
+        let key_path = dir.join("rootCA.key").to_string_lossy().into_owned();
+        let ca = CertificateAuthority::generate("Test Root").unwrap();
+        ca.save(&cert_path, &key_path).unwrap();
+        let loaded = CertificateAuthority::load(&cert_path, &key_path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.cert(), ca.cert());
+    }
+
+    #[test]
+    fn rejects_invalid_pem() {
+        let ca = CertificateAuthority::generate("Test Root").unwrap();
+        assert!(matches!(
+            CertificateAuthority::from_pem(ca.cert_pem(), "not a key"),
+            Err(CaError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            CertificateAuthority::from_pem("not a certificate", &ca.key_pem()),
+            Err(CaError::InvalidCertificate)
+        ));
+    }
+
+    #[test]
+    fn exports_root_certificate() {
+        let ca = CertificateAuthority::generate("Test Root").unwrap();
+        let pem = ca.export(ExportFormat::Pem, "").unwrap();
+        assert!(pem.starts_with(b"-----BEGIN CERTIFICATE-----"));
+        assert_eq!(
+            ca.export(ExportFormat::Der, "").unwrap(),
+            ca.cert().to_vec()
+        );
+
+        let p12 = ca.export(ExportFormat::Pkcs12, "secret").unwrap();
+        let store = KeyStore::from_pkcs12(&p12, "secret").unwrap();
+        match store.entry(PKCS12_ALIAS) {
+            Some(KeyStoreEntry::Certificate(cert)) => assert_eq!(cert.as_der(), &ca.cert()[..]),
+            other => panic!("unexpected entry {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_export_formats() {
+        assert_eq!("PEM".parse::<ExportFormat>().unwrap(), ExportFormat::Pem);
+        assert_eq!("cer".parse::<ExportFormat>().unwrap(), ExportFormat::Der);
+        assert_eq!("pfx".parse::<ExportFormat>().unwrap(), ExportFormat::Pkcs12);
+        assert!("jks".parse::<ExportFormat>().is_err());
+    }
+
+    #[test]
+    fn issues_leaf_signed_by_root() {
+        let ca = CertificateAuthority::generate("Test Root").unwrap();
+        let certified = ca.issue("example.com", &provider()).unwrap();
+        assert_eq!(certified.cert.len(), 2);
+        assert_eq!(certified.cert[1], *ca.cert());
+
+        let (_, root) = X509Certificate::from_der(ca.cert()).unwrap();
+        let (_, leaf) = X509Certificate::from_der(&certified.cert[0]).unwrap();
+        assert_eq!(leaf.issuer(), root.subject());
+        assert!(leaf.verify_signature(Some(root.public_key())).is_ok());
+        let names = leaf.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(
+            names.value.general_names,
+            [GeneralName::DNSName("example.com")]
+        );
+    }
+
+    #[test]
+    fn issues_leaf_for_ip() {
+        let ca = CertificateAuthority::generate("Test Root").unwrap();
+        let certified = ca.issue("127.0.0.1", &provider()).unwrap();
+        let (_, leaf) = X509Certificate::from_der(&certified.cert[0]).unwrap();
+        let names = leaf.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(
+            names.value.general_names,
+            [GeneralName::IPAddress(&[127, 0, 0, 1])]
+        );
+    }
+}
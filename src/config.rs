@@ -1,5 +1,25 @@
+use crate::dto::rewrite::parse_rewrite_rules;
+use crate::proxy::allowlist::ClientAllowlist;
+use crate::proxy::auth::{ProxyAuth, ProxyCredentials};
+use crate::proxy::capture::DEFAULT_CAPTURE_LIMIT;
+use crate::proxy::client::ClientSettings;
+use crate::proxy::host_pattern;
+use crate::proxy::intercept::InterceptSettings;
+use crate::proxy::passthrough::PassthroughRules;
+use crate::proxy::resolver::ResolverSettings;
+use crate::proxy::reverse::ReverseProxySettings;
+use crate::proxy::rewrite::RewriteRule;
+use crate::proxy::scope::CaptureScope;
+use crate::proxy::shutdown::DEFAULT_DRAIN_TIMEOUT;
+use crate::proxy::socks;
+use crate::proxy::timeouts::Timeouts;
+use crate::proxy::upstream_proxy::UpstreamProxySettings;
+use crate::proxy::upstream_tls::UpstreamTlsSettings;
+use ipnet::IpNet;
 use std::collections::HashMap;
 use std::env;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
 use thiserror::Error;
 
 pub struct Config {
@@ -10,6 +30,24 @@ pub struct Config {
     mongodb_uri: String,
     api_host: String,
     api_port: u16,
+    upstream_tls: UpstreamTlsSettings,
+    client: ClientSettings,
+    capture_limit: usize,
+    capture_scope: CaptureScope,
+    passthrough: PassthroughRules,
+    socks_port: Option<u16>,
+    socks_credentials: Option<ProxyCredentials>,
+    auth: Option<ProxyAuth>,
+    allowed_clients: Vec<IpNet>,
+    transparent_port: Option<u16>,
+    reverse_port: Option<u16>,
+    reverse: ReverseProxySettings,
+    intercept: InterceptSettings,
+    control_port: Option<u16>,
+    control_host: IpAddr,
+    rewrite_rules: Vec<RewriteRule>,
+    drain_timeout: Duration,
+    timeouts: Timeouts,
 }
 
 mod rusty_env {
@@ -21,6 +59,46 @@ mod rusty_env {
     pub const API_HOST: &str = "RUSTY_PROXY_API_HOST";
     pub const API_PORT: &str = "RUSTY_PROXY_API_PORT";
 
+    // Optional parameters
+    pub const UPSTREAM_TLS: &str = "RUSTY_PROXY_UPSTREAM_TLS";
+    pub const UPSTREAM_TLS_HOSTS: &str = "RUSTY_PROXY_UPSTREAM_TLS_HOSTS";
+    pub const UPSTREAM_CLIENT_CERTS: &str = "RUSTY_PROXY_UPSTREAM_CLIENT_CERTS";
+    pub const POOL_IDLE_TIMEOUT: &str = "RUSTY_PROXY_POOL_IDLE_TIMEOUT";
+    pub const POOL_MAX_IDLE_PER_HOST: &str = "RUSTY_PROXY_POOL_MAX_IDLE_PER_HOST";
+    pub const UPSTREAM_HTTP2: &str = "RUSTY_PROXY_UPSTREAM_HTTP2";
+    pub const UPSTREAM_H2C_HOSTS: &str = "RUSTY_PROXY_UPSTREAM_H2C_HOSTS";
+    pub const UPSTREAM_PROXY: &str = "RUSTY_PROXY_UPSTREAM_PROXY";
+    pub const UPSTREAM_PROXY_HOSTS: &str = "RUSTY_PROXY_UPSTREAM_PROXY_HOSTS";
+    pub const DNS_OVERRIDES: &str = "RUSTY_PROXY_DNS_OVERRIDES";
+    pub const DNS_SERVERS: &str = "RUSTY_PROXY_DNS_SERVERS";
+    pub const DNS_CACHE_SIZE: &str = "RUSTY_PROXY_DNS_CACHE_SIZE";
+    pub const CAPTURE_LIMIT: &str = "RUSTY_PROXY_CAPTURE_LIMIT";
+    pub const SCOPE_INCLUDE: &str = "RUSTY_PROXY_SCOPE_INCLUDE";
+    pub const SCOPE_EXCLUDE: &str = "RUSTY_PROXY_SCOPE_EXCLUDE";
+    pub const INTERCEPT_HOSTS: &str = "RUSTY_PROXY_INTERCEPT_HOSTS";
+    pub const PASSTHROUGH_HOSTS: &str = "RUSTY_PROXY_PASSTHROUGH_HOSTS";
+    pub const SOCKS_PORT: &str = "RUSTY_PROXY_SOCKS_PORT";
+    pub const SOCKS_CREDENTIALS: &str = "RUSTY_PROXY_SOCKS_CREDENTIALS";
+    pub const AUTH_USERS: &str = "RUSTY_PROXY_AUTH_USERS";
+    pub const ALLOWED_CLIENTS: &str = "RUSTY_PROXY_ALLOWED_CLIENTS";
+    pub const TRANSPARENT_PORT: &str = "RUSTY_PROXY_TRANSPARENT_PORT";
+    pub const REVERSE_PORT: &str = "RUSTY_PROXY_REVERSE_PORT";
+    pub const REVERSE_ROUTES: &str = "RUSTY_PROXY_REVERSE_ROUTES";
+    pub const REVERSE_PRESERVE_HOST: &str = "RUSTY_PROXY_REVERSE_PRESERVE_HOST";
+    pub const REVERSE_REWRITE_LOCATION: &str = "RUSTY_PROXY_REVERSE_REWRITE_LOCATION";
+    pub const BREAKPOINTS: &str = "RUSTY_PROXY_BREAKPOINTS";
+    pub const BREAKPOINT_TIMEOUT: &str = "RUSTY_PROXY_BREAKPOINT_TIMEOUT";
+    pub const BREAKPOINT_TIMEOUT_ACTION: &str = "RUSTY_PROXY_BREAKPOINT_TIMEOUT_ACTION";
+    pub const CONTROL_PORT: &str = "RUSTY_PROXY_CONTROL_PORT";
+    pub const CONTROL_HOST: &str = "RUSTY_PROXY_CONTROL_HOST";
+    pub const REWRITE_RULES: &str = "RUSTY_PROXY_REWRITE_RULES";
+    pub const SHUTDOWN_TIMEOUT: &str = "RUSTY_PROXY_SHUTDOWN_TIMEOUT";
+    pub const CONNECT_TIMEOUT: &str = "RUSTY_PROXY_CONNECT_TIMEOUT";
+    pub const TLS_HANDSHAKE_TIMEOUT: &str = "RUSTY_PROXY_TLS_HANDSHAKE_TIMEOUT";
+    pub const HEADER_READ_TIMEOUT: &str = "RUSTY_PROXY_HEADER_READ_TIMEOUT";
+    pub const RESPONSE_TIMEOUT: &str = "RUSTY_PROXY_RESPONSE_TIMEOUT";
+    pub const IDLE_TIMEOUT: &str = "RUSTY_PROXY_IDLE_TIMEOUT";
+
     pub const ALL_PARAMS: [&str; 7] = [
         PROXY_HOST,
         PROXY_PORT,
@@ -61,6 +139,90 @@ impl Config {
         self.api_port
     }
 
+    pub fn upstream_tls(&self) -> &UpstreamTlsSettings {
+        &self.upstream_tls
+    }
+
+    pub fn client(&self) -> &ClientSettings {
+        &self.client
+    }
+
+    pub fn capture_limit(&self) -> usize {
+        self.capture_limit
+    }
+
+    pub fn capture_scope(&self) -> &CaptureScope {
+        &self.capture_scope
+    }
+
+    pub fn passthrough(&self) -> &PassthroughRules {
+        &self.passthrough
+    }
+
+    pub fn socks_port(&self) -> Option<u16> {
+        self.socks_port
+    }
+
+    pub fn socks_credentials(&self) -> Option<&ProxyCredentials> {
+        self.socks_credentials.as_ref()
+    }
+
+    pub fn auth(&self) -> Option<&ProxyAuth> {
+        self.auth.as_ref()
+    }
+
+    pub fn allowed_clients(&self) -> &Vec<IpNet> {
+        &self.allowed_clients
+    }
+
+    pub fn transparent_port(&self) -> Option<u16> {
+        self.transparent_port
+    }
+
+    pub fn reverse_port(&self) -> Option<u16> {
+        self.reverse_port
+    }
+
+    pub fn reverse(&self) -> &ReverseProxySettings {
+        &self.reverse
+    }
+
+    pub fn intercept(&self) -> &InterceptSettings {
+        &self.intercept
+    }
+
+    pub fn control_port(&self) -> Option<u16> {
+        self.control_port
+    }
+
+    pub fn control_host(&self) -> IpAddr {
+        self.control_host
+    }
+
+    pub fn rewrite_rules(&self) -> &Vec<RewriteRule> {
+        &self.rewrite_rules
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    // Paths of the root certificate and its key, without requiring the rest of the config
+    pub fn ca_paths_from_env() -> Result<(String, String), ConfigParsingError> {
+        let read = |param_name: &str| {
+            env::var(param_name)
+                .map_err(|_| ConfigParsingError::MissingParameter(param_name.to_string()))
+        };
+        Ok((
+            read(rusty_env::SSL_CERTIFICATE)?,
+            read(rusty_env::SSL_PRIVATE_KEY)?,
+        ))
+    }
+
     pub fn from_env() -> Result<Self, ConfigParsingError> {
         let mut raw_config = HashMap::new();
         for &param_name in rusty_env::ALL_PARAMS.iter() {
@@ -68,7 +230,157 @@ impl Config {
                 .map_err(|_| ConfigParsingError::MissingParameter(param_name.to_string()))?;
             raw_config.insert(param_name, param_value);
         }
-        return Ok(Config {
+
+        let mut upstream_tls = UpstreamTlsSettings::default();
+        if let Some(policy) = optional_param(rusty_env::UPSTREAM_TLS) {
+            upstream_tls.default = policy
+                .parse()
+                .map_err(|cause| invalid_value(rusty_env::UPSTREAM_TLS, cause))?;
+        }
+        if let Some(hosts) = optional_param(rusty_env::UPSTREAM_TLS_HOSTS) {
+            upstream_tls.hosts = UpstreamTlsSettings::parse_hosts(&hosts)
+                .map_err(|cause| invalid_value(rusty_env::UPSTREAM_TLS_HOSTS, cause))?;
+        }
+        if let Some(client_certs) = optional_param(rusty_env::UPSTREAM_CLIENT_CERTS) {
+            upstream_tls.client_certs = UpstreamTlsSettings::parse_client_certs(&client_certs)
+                .map_err(|cause| invalid_value(rusty_env::UPSTREAM_CLIENT_CERTS, cause))?;
+        }
+
+        let mut client = ClientSettings::default();
+        if let Some(secs) = optional_parsed::<u64>(rusty_env::POOL_IDLE_TIMEOUT, "u64")? {
+            client.pool.idle_timeout = Duration::from_secs(secs);
+        }
+        if let Some(max_idle) = optional_parsed(rusty_env::POOL_MAX_IDLE_PER_HOST, "usize")? {
+            client.pool.max_idle_per_host = max_idle;
+        }
+        if let Some(http2) = optional_parsed(rusty_env::UPSTREAM_HTTP2, "bool")? {
+            client.http2 = http2;
+        }
+        if let Some(hosts) = optional_param(rusty_env::UPSTREAM_H2C_HOSTS) {
+            client.h2c_hosts = host_pattern::parse_list(&hosts)
+                .map_err(|cause| invalid_value(rusty_env::UPSTREAM_H2C_HOSTS, cause))?;
+        }
+        if let Some(proxy) = optional_param(rusty_env::UPSTREAM_PROXY) {
+            client.proxy.default = UpstreamProxySettings::parse_proxy(&proxy)
+                .map_err(|cause| invalid_value(rusty_env::UPSTREAM_PROXY, cause))?;
+        }
+        if let Some(hosts) = optional_param(rusty_env::UPSTREAM_PROXY_HOSTS) {
+            client.proxy.hosts = UpstreamProxySettings::parse_hosts(&hosts)
+                .map_err(|cause| invalid_value(rusty_env::UPSTREAM_PROXY_HOSTS, cause))?;
+        }
+        if let Some(overrides) = optional_param(rusty_env::DNS_OVERRIDES) {
+            client.resolver.overrides = ResolverSettings::parse_overrides(&overrides)
+                .map_err(|cause| invalid_value(rusty_env::DNS_OVERRIDES, cause))?;
+        }
+        if let Some(servers) = optional_param(rusty_env::DNS_SERVERS) {
+            client.resolver.nameservers = ResolverSettings::parse_nameservers(&servers)
+                .map_err(|cause| invalid_value(rusty_env::DNS_SERVERS, cause))?;
+        }
+        if let Some(cache_size) = optional_parsed(rusty_env::DNS_CACHE_SIZE, "usize")? {
+            client.resolver.cache_size = cache_size;
+        }
+
+        let capture_limit =
+            optional_parsed(rusty_env::CAPTURE_LIMIT, "usize")?.unwrap_or(DEFAULT_CAPTURE_LIMIT);
+
+        let mut capture_scope = CaptureScope::default();
+        if let Some(rules) = optional_param(rusty_env::SCOPE_INCLUDE) {
+            capture_scope.include = CaptureScope::parse_rules(&rules)
+                .map_err(|cause| invalid_value(rusty_env::SCOPE_INCLUDE, cause))?;
+        }
+        if let Some(rules) = optional_param(rusty_env::SCOPE_EXCLUDE) {
+            capture_scope.exclude = CaptureScope::parse_rules(&rules)
+                .map_err(|cause| invalid_value(rusty_env::SCOPE_EXCLUDE, cause))?;
+        }
+
+        let mut passthrough = PassthroughRules::default();
+        if let Some(hosts) = optional_param(rusty_env::INTERCEPT_HOSTS) {
+            passthrough.intercept = host_pattern::parse_list(&hosts)
+                .map_err(|cause| invalid_value(rusty_env::INTERCEPT_HOSTS, cause))?;
+        }
+        if let Some(hosts) = optional_param(rusty_env::PASSTHROUGH_HOSTS) {
+            passthrough.passthrough = host_pattern::parse_list(&hosts)
+                .map_err(|cause| invalid_value(rusty_env::PASSTHROUGH_HOSTS, cause))?;
+        }
+
+        let socks_port = optional_parsed(rusty_env::SOCKS_PORT, "u16")?;
+        let socks_credentials = optional_param(rusty_env::SOCKS_CREDENTIALS)
+            .map(|credentials| socks::parse_credentials(&credentials))
+            .transpose()
+            .map_err(|cause| invalid_value(rusty_env::SOCKS_CREDENTIALS, cause))?;
+        let auth = optional_param(rusty_env::AUTH_USERS)
+            .map(|users| ProxyAuth::parse_users(&users))
+            .transpose()
+            .map_err(|cause| invalid_value(rusty_env::AUTH_USERS, cause))?
+            .map(|users| ProxyAuth { users });
+        let allowed_clients = match optional_param(rusty_env::ALLOWED_CLIENTS) {
+            Some(networks) => ClientAllowlist::parse_networks(&networks)
+                .map_err(|cause| invalid_value(rusty_env::ALLOWED_CLIENTS, cause))?,
+            None => Vec::new(),
+        };
+        let transparent_port = optional_parsed(rusty_env::TRANSPARENT_PORT, "u16")?;
+
+        let reverse_port = optional_parsed(rusty_env::REVERSE_PORT, "u16")?;
+        let mut reverse = ReverseProxySettings::default();
+        if let Some(routes) = optional_param(rusty_env::REVERSE_ROUTES) {
+            reverse.routes = ReverseProxySettings::parse_routes(&routes)
+                .map_err(|cause| invalid_value(rusty_env::REVERSE_ROUTES, cause))?;
+        }
+        if let Some(preserve_host) = optional_parsed(rusty_env::REVERSE_PRESERVE_HOST, "bool")? {
+            reverse.preserve_host = preserve_host;
+        }
+        if let Some(rewrite) = optional_parsed(rusty_env::REVERSE_REWRITE_LOCATION, "bool")? {
+            reverse.rewrite_location = rewrite;
+        }
+
+        let mut intercept = InterceptSettings::default();
+        if let Some(rules) = optional_param(rusty_env::BREAKPOINTS) {
+            intercept.rules = InterceptSettings::parse_rules(&rules)
+                .map_err(|cause| invalid_value(rusty_env::BREAKPOINTS, cause))?;
+        }
+        if let Some(secs) = optional_parsed::<u64>(rusty_env::BREAKPOINT_TIMEOUT, "u64")? {
+            intercept.timeout = Duration::from_secs(secs);
+        }
+        if let Some(action) = optional_param(rusty_env::BREAKPOINT_TIMEOUT_ACTION) {
+            intercept.on_timeout = action
+                .parse()
+                .map_err(|cause| invalid_value(rusty_env::BREAKPOINT_TIMEOUT_ACTION, cause))?;
+        }
+        let control_port = optional_parsed(rusty_env::CONTROL_PORT, "u16")?;
+        // The control api can change traffic, so it is reachable only locally unless configured
+        let control_host = optional_parsed(rusty_env::CONTROL_HOST, "ip address")?
+            .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        // Rules are kept in a JSON file since they may contain any characters
+        let rewrite_rules = match optional_param(rusty_env::REWRITE_RULES) {
+            Some(path) => std::fs::read_to_string(path.trim())
+                .map_err(|e| e.to_string())
+                .and_then(|json| parse_rewrite_rules(&json))
+                .map_err(|cause| invalid_value(rusty_env::REWRITE_RULES, cause))?,
+            None => Vec::new(),
+        };
+
+        let drain_timeout = optional_parsed::<u64>(rusty_env::SHUTDOWN_TIMEOUT, "u64")?
+            .map_or(DEFAULT_DRAIN_TIMEOUT, Duration::from_secs);
+
+        let mut timeouts = Timeouts::default();
+        for (param_name, timeout) in [
+            (rusty_env::CONNECT_TIMEOUT, &mut timeouts.connect),
+            (
+                rusty_env::TLS_HANDSHAKE_TIMEOUT,
+                &mut timeouts.tls_handshake,
+            ),
+            (rusty_env::HEADER_READ_TIMEOUT, &mut timeouts.header_read),
+            (rusty_env::RESPONSE_TIMEOUT, &mut timeouts.response),
+            (rusty_env::IDLE_TIMEOUT, &mut timeouts.idle),
+        ] {
+            // Zero seconds disables the limit
+            if let Some(secs) = optional_parsed::<u64>(param_name, "u64")? {
+                *timeout = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+        }
+
+        Ok(Config {
             proxy_host: raw_config.get(rusty_env::PROXY_HOST).unwrap().clone(),
             proxy_port: raw_config
                 .get(rusty_env::PROXY_PORT)
@@ -93,7 +405,55 @@ impl Config {
                     param_name: rusty_env::PROXY_PORT.to_string(),
                     expected: "u16".to_string(),
                 })?,
-        });
+            upstream_tls,
+            client,
+            capture_limit,
+            capture_scope,
+            passthrough,
+            socks_port,
+            socks_credentials,
+            auth,
+            allowed_clients,
+            transparent_port,
+            reverse_port,
+            reverse,
+            intercept,
+            control_port,
+            control_host,
+            rewrite_rules,
+            drain_timeout,
+            timeouts,
+        })
+    }
+}
+
+fn optional_param(param_name: &str) -> Option<String> {
+    env::var(param_name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn optional_parsed<T: std::str::FromStr>(
+    param_name: &str,
+    expected: &str,
+) -> Result<Option<T>, ConfigParsingError> {
+    optional_param(param_name)
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| ConfigParsingError::InvalidParameterType {
+                    param_name: param_name.to_string(),
+                    expected: expected.to_string(),
+                })
+        })
+        .transpose()
+}
+
+fn invalid_value(param_name: &str, cause: String) -> ConfigParsingError {
+    ConfigParsingError::InvalidParameterValue {
+        param_name: param_name.to_string(),
+        cause,
     }
 }
 
@@ -105,6 +465,9 @@ pub enum ConfigParsingError {
         expected: String,
     },
 
+    #[error("invalid value of parameter {param_name:?}: {cause}")]
+    InvalidParameterValue { param_name: String, cause: String },
+
     #[error("missing parameter {0:?}")]
     MissingParameter(String),
 }
@@ -0,0 +1,21 @@
+use crate::proxy::allowlist;
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ClientAllowlist {
+    // Empty when every client is accepted
+    pub networks: Vec<String>,
+    pub rejected: u64,
+}
+
+impl From<&allowlist::ClientAllowlist> for ClientAllowlist {
+    fn from(allowlist: &allowlist::ClientAllowlist) -> Self {
+        ClientAllowlist {
+            networks: allowlist
+                .networks()
+                .iter()
+                .map(|network| network.to_string())
+                .collect(),
+            rejected: allowlist.rejected(),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::{self, Read};
+
+use bytes::Bytes;
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+
+// Decoded body, cut at the limit when it is longer
+#[derive(Debug)]
+pub struct Decoded {
+    pub bytes: Bytes,
+    pub truncated: bool,
+}
+
+// Undo the Content-Encoding of a captured body. Codings are listed in the order they were
+// applied, so they are removed from the last one. Decoding stops after `limit` bytes,
+// so a small body can not expand without bound
+pub fn decode(content_encoding: &str, body: Bytes, limit: usize) -> io::Result<Decoded> {
+    let mut decoded = Decoded {
+        bytes: body,
+        truncated: false,
+    };
+    for coding in content_encoding.rsplit(',').map(str::trim) {
+        let input = &decoded.bytes[..];
+        let output = match coding.to_ascii_lowercase().as_str() {
+            "" | "identity" => continue,
+            "gzip" | "x-gzip" => read_decoded(MultiGzDecoder::new(input), limit)?,
+            // Some servers send raw deflate instead of the zlib format
+            "deflate" => read_decoded(ZlibDecoder::new(input), limit)
+                .or_else(|_| read_decoded(DeflateDecoder::new(input), limit))?,
+            "br" => read_decoded(brotli::Decompressor::new(input, 4096), limit)?,
+            "zstd" => read_decoded(zstd::stream::Decoder::new(input)?, limit)?,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("unsupported content coding {:?}", other),
+                ))
+            }
+        };
+        decoded = Decoded {
+            bytes: output.bytes,
+            truncated: decoded.truncated || output.truncated,
+        };
+    }
+    Ok(decoded)
+}
+
+// Captures may be truncated, so whatever was decoded before an error is kept
+fn read_decoded<R: Read>(reader: R, limit: usize) -> io::Result<Decoded> {
+    // One byte over the limit tells a body of exactly `limit` bytes from a longer one
+    let mut reader = reader.take((limit as u64).saturating_add(1));
+    let mut decoded = Vec::new();
+    let mut chunk = [0u8; 8192];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => decoded.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if decoded.is_empty() => return Err(e),
+            Err(_) => break,
+        }
+    }
+    let truncated = decoded.len() > limit;
+    decoded.truncate(limit);
+    Ok(Decoded {
+        bytes: Bytes::from(decoded),
+        truncated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Bytes {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        Bytes::from(encoder.finish().unwrap())
+    }
+
+    #[test]
+    fn decodes_gzip() {
+        let decoded = decode("gzip", gzip(b"hello"), 1024).unwrap();
+        assert_eq!(&decoded.bytes[..], b"hello");
+        assert!(!decoded.truncated);
+    }
+
+    #[test]
+    fn removes_codings_from_the_last_one() {
+        let twice = gzip(&gzip(b"hello"));
+        let decoded = decode("gzip, identity, gzip", twice, 1024).unwrap();
+        assert_eq!(&decoded.bytes[..], b"hello");
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let decoded = decode("gzip", gzip(&[b'a'; 100_000]), 1000).unwrap();
+        assert_eq!(decoded.bytes.len(), 1000);
+        assert!(decoded.truncated);
+
+        let exact = decode("gzip", gzip(&[b'a'; 1000]), 1000).unwrap();
+        assert_eq!(exact.bytes.len(), 1000);
+        assert!(!exact.truncated);
+    }
+
+    #[test]
+    fn keeps_the_decoded_part_of_a_cut_body() {
+        let compressed = gzip(&[b'a'; 100_000]);
+        let cut = compressed.slice(..compressed.len() / 2);
+        let decoded = decode("gzip", cut, 1 << 20).unwrap();
+        assert!(!decoded.bytes.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_codings() {
+        let err = decode("compress", Bytes::from_static(b"x"), 1024).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
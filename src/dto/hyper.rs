@@ -1,8 +1,9 @@
 use std::string::FromUtf8Error;
 
+use crate::proxy::capture::DEFAULT_CAPTURE_LIMIT;
 use crate::proxy::BodyType;
 
-use super::{body::SimpleBody, Request, Response};
+use super::{body::SimpleBody, encoding, Request, Response};
 use bytes::Bytes;
 use http_body_util::{BodyExt, Full};
 use log::debug;
@@ -35,24 +36,21 @@ impl From<HyperRequest> for Request {
                 .collect()
         });
 
-        let cookies = headers
-            .get(http::header::COOKIE)
-            .clone()
-            .map(|cookie_value| {
-                cookie_value
-                    .clone()
-                    .to_str()
-                    .unwrap()
-                    .split(';')
-                    .map(|cookie| {
-                        let cookie_pair: Vec<&str> = cookie.split('=').collect();
-                        return (
-                            cookie_pair[0].trim().to_string(),
-                            cookie_pair.get(1).map_or("".to_string(), |s| s.to_string()),
-                        );
-                    })
-                    .collect()
-            });
+        let cookies = headers.get(http::header::COOKIE).map(|cookie_value| {
+            cookie_value
+                .clone()
+                .to_str()
+                .unwrap()
+                .split(';')
+                .map(|cookie| {
+                    let cookie_pair: Vec<&str> = cookie.split('=').collect();
+                    (
+                        cookie_pair[0].trim().to_string(),
+                        cookie_pair.get(1).map_or("".to_string(), |s| s.to_string()),
+                    )
+                })
+                .collect()
+        });
 
         let is_urlencoded = headers
             .get(http::header::CONTENT_TYPE)
@@ -80,16 +78,16 @@ impl From<HyperRequest> for Request {
     }
 }
 
-impl Into<(http::request::Request<BodyType>, bool)> for Request {
-    fn into(mut self) -> (http::request::Request<BodyType>, bool) {
-        let path_and_query = match self.query_params {
+impl From<Request> for (http::request::Request<BodyType>, bool) {
+    fn from(mut req: Request) -> Self {
+        let path_and_query = match req.query_params {
             Some(query_params) => {
                 let query = query_params
                     .flat_iter()
                     .map(|(k, v)| {
                         let mut result = String::new();
                         result.push_str(k);
-                        result.push_str("=");
+                        result.push('=');
                         result.push_str(v);
                         result
                     })
@@ -99,30 +97,30 @@ impl Into<(http::request::Request<BodyType>, bool)> for Request {
                         acc
                     })
                     .unwrap();
-                self.path.push_str("?");
-                self.path.push_str(query.as_str());
-                self.path
+                req.path.push('?');
+                req.path.push_str(query.as_str());
+                req.path
             }
-            None => self.path,
+            None => req.path,
         };
         debug!("{:?}", path_and_query);
         let uri = http::Uri::builder()
             .path_and_query(path_and_query)
             .build()
             .unwrap();
-        let mut req = http::request::Builder::new()
-            .method(self.method.as_str())
+        let mut builder = http::request::Builder::new()
+            .method(req.method.as_str())
             .uri(uri);
-        for (query_name, query_val) in self.headers.flat_iter() {
-            req = req.header(query_name.clone(), query_val.clone());
+        for (query_name, query_val) in req.headers.flat_iter() {
+            builder = builder.header(query_name.clone(), query_val.clone());
         }
-        if let Some(cookies) = self.cookies {
+        if let Some(cookies) = req.cookies {
             let cookie_value = cookies
                 .into_iter()
                 .map(|(k, v)| {
                     let mut result = String::new();
                     result.push_str(k.as_str());
-                    result.push_str("=");
+                    result.push('=');
                     result.push_str(v.as_str());
                     result
                 })
@@ -132,11 +130,11 @@ impl Into<(http::request::Request<BodyType>, bool)> for Request {
                     acc
                 })
                 .unwrap();
-            req = req.header(http::header::COOKIE, cookie_value);
+            builder = builder.header(http::header::COOKIE, cookie_value);
         };
-        let req = req
+        let request = builder
             .body(
-                match self.body {
+                match req.body {
                     SimpleBody::Blob(b) => Full::new(Bytes::from(b)),
                     SimpleBody::UrlEncoded(b) => {
                         let mut data = UrlEncodedData::from("?");
@@ -150,22 +148,49 @@ impl Into<(http::request::Request<BodyType>, bool)> for Request {
                 .boxed(),
             )
             .unwrap();
-        (req, self.is_https)
+        (request, req.is_https)
     }
 }
 
+// Decoded bodies are cut at the default capture limit unless a limit is given
 impl From<HyperResponse> for Response {
-    fn from((parts, body): HyperResponse) -> Self {
+    fn from(response: HyperResponse) -> Self {
+        Response::decoded(response, DEFAULT_CAPTURE_LIMIT)
+    }
+}
+
+impl Response {
+    // The body is decoded up to `limit` bytes, a longer one is cut and marked truncated
+    pub fn decoded((parts, body): HyperResponse, limit: usize) -> Self {
         let http::response::Parts {
-            status, headers, ..
+            status,
+            version,
+            headers,
+            ..
         } = parts;
         let is_urlencoded = headers
             .get(http::header::CONTENT_TYPE)
             .iter()
             .find(|&value| value.to_str().unwrap() == MIME_URL_ENCODED)
             .is_some();
-        let code = status.clone().into();
+        let code = status.into();
         let message = status.canonical_reason().unwrap().to_string();
+        let encoding = headers
+            .get(http::header::CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        // The client gets the body as sent, only the captured copy is decoded
+        let (body, truncated) = match &encoding {
+            Some(content_encoding) => match encoding::decode(content_encoding, body.clone(), limit)
+            {
+                Ok(decoded) => (decoded.bytes, decoded.truncated),
+                Err(e) => {
+                    debug!("Keeping {} response body encoded: {}", content_encoding, e);
+                    (body, false)
+                }
+            },
+            None => (body, false),
+        };
         let headers = headers
             .iter()
             .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
@@ -174,9 +199,12 @@ impl From<HyperResponse> for Response {
         let body = parse_body(body, is_urlencoded);
 
         Response {
+            version: format!("{:?}", version),
             code,
             message,
             headers,
+            encoding,
+            truncated,
             body,
         }
     }
@@ -208,5 +236,5 @@ fn body_as_url_encoded(b: HyperBody) -> Result<MultiMap<String, String>, FromUtf
         .for_each(|(name, val)| {
             map.insert(name.to_string(), val.to_string());
         });
-    return Ok(map);
+    Ok(map)
 }
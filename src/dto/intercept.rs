@@ -0,0 +1,152 @@
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+
+use super::{Request, Response};
+use crate::proxy::intercept::{self, Edit};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::Bytes;
+use http::uri::PathAndQuery;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use multimap::MultiMap;
+
+// Request or response held by the proxy, responses are shown along with their request
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct HeldMessage {
+    pub id: u64,
+    pub exchange_id: String,
+    // "request" or "response"
+    pub stage: String,
+    pub request: Request,
+    pub response: Option<Response>,
+    // Milliseconds since the unix epoch
+    pub held_at: u64,
+}
+
+impl From<intercept::HeldMessage> for HeldMessage {
+    fn from(message: intercept::HeldMessage) -> Self {
+        // Request bodies are not kept once the request is sent
+        let (request_body, response) = match message.response {
+            Some(parts) => (Bytes::new(), Some(Response::from((parts, message.body)))),
+            None => (message.body, None),
+        };
+        HeldMessage {
+            id: message.id,
+            exchange_id: message.exchange_id,
+            stage: message.stage.to_string(),
+            request: Request::from((message.request, request_body, message.is_https)),
+            response,
+            held_at: message
+                .held_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+        }
+    }
+}
+
+// Changes to a held message, everything not set is forwarded as is
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct InterceptEdit {
+    pub method: Option<String>,
+    // Path with the query
+    pub path: Option<String>,
+    pub status: Option<u16>,
+    // Replace all values of these headers
+    pub headers: MultiMap<String, String>,
+    pub remove_headers: Vec<String>,
+    // Text body
+    pub body: Option<String>,
+    // Binary body encoded with base64, can not be set along with `body`
+    pub body_base64: Option<String>,
+}
+
+impl TryFrom<InterceptEdit> for Edit {
+    type Error = String;
+
+    fn try_from(edit: InterceptEdit) -> Result<Self, Self::Error> {
+        let method = edit
+            .method
+            .map(|method| {
+                Method::from_str(&method).map_err(|_| format!("invalid method {:?}", method))
+            })
+            .transpose()?;
+        let path = edit
+            .path
+            .map(|path| {
+                PathAndQuery::from_str(&path).map_err(|_| format!("invalid path {:?}", path))
+            })
+            .transpose()?;
+        let status = edit
+            .status
+            .map(|status| {
+                StatusCode::from_u16(status).map_err(|_| format!("invalid status {}", status))
+            })
+            .transpose()?;
+        let mut set_headers = HeaderMap::new();
+        for (name, value) in edit.headers.flat_iter() {
+            set_headers.append(header_name(name)?, header_value(value)?);
+        }
+        let remove_headers = edit
+            .remove_headers
+            .iter()
+            .map(|name| header_name(name))
+            .collect::<Result<_, _>>()?;
+        let body = match (edit.body, edit.body_base64) {
+            (Some(_), Some(_)) => return Err("body and body_base64 are both set".to_string()),
+            (Some(body), None) => Some(Bytes::from(body)),
+            (None, Some(encoded)) => Some(Bytes::from(
+                BASE64_STANDARD
+                    .decode(encoded.trim())
+                    .map_err(|e| format!("invalid base64 body: {}", e))?,
+            )),
+            (None, None) => None,
+        };
+        Ok(Edit {
+            method,
+            path,
+            status,
+            set_headers,
+            remove_headers,
+            body,
+        })
+    }
+}
+
+fn header_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_str(name).map_err(|_| format!("invalid header name {:?}", name))
+}
+
+fn header_value(value: &str) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(value).map_err(|_| format!("invalid header value {:?}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(json: &str) -> Result<Edit, String> {
+        Edit::try_from(serde_json::from_str::<InterceptEdit>(json).unwrap())
+    }
+
+    #[test]
+    fn accepts_text_and_binary_bodies() {
+        let text = edit(r#"{"body": "hello"}"#).unwrap();
+        assert_eq!(text.body.unwrap(), "hello");
+        let binary = edit(r#"{"body_base64": "AP8Q"}"#).unwrap();
+        assert_eq!(binary.body.unwrap(), &[0x00, 0xff, 0x10][..]);
+        assert!(edit("{}").unwrap().body.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_bodies() {
+        assert!(edit(r#"{"body_base64": "not base64!"}"#).is_err());
+        assert!(edit(r#"{"body": "a", "body_base64": "YQ=="}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        assert!(edit(r#"{"method": "BAD METHOD"}"#).is_err());
+        assert!(edit(r#"{"status": 1000}"#).is_err());
+        assert!(edit(r#"{"headers": {"bad header": ["x"]}}"#).is_err());
+    }
+}
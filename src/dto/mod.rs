@@ -1,10 +1,19 @@
+pub mod allowlist;
 pub mod body;
+pub mod encoding;
 pub mod hyper;
+pub mod intercept;
 pub mod reqresp;
 pub mod request;
 pub mod response;
+pub mod rewrite;
+pub mod websocket;
 
+pub use allowlist::ClientAllowlist;
 pub use body::SimpleBody;
+pub use intercept::{HeldMessage, InterceptEdit};
 pub use reqresp::Reqresp;
 pub use request::Request;
 pub use response::Response;
+pub use rewrite::RewriteRule;
+pub use websocket::WebSocketFrame;
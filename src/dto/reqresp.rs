@@ -6,6 +6,15 @@ pub struct Reqresp {
     pub id: String,
     pub req: Request,
     pub resp: Response,
+    // Bodies were cut at the capture limit
+    #[serde(default)]
+    pub truncated: bool,
+    // Authenticated user who made the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    // Upstream failure the response was made up for, client timeout or interrupted body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 impl Reqresp {
@@ -14,6 +23,9 @@ impl Reqresp {
             id: String::new(),
             req,
             resp,
+            truncated: false,
+            user: None,
+            error: None,
         }
     }
 }
@@ -3,13 +3,26 @@ use multimap::MultiMap;
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Response {
+    // Protocol negotiated with the upstream, empty for exchanges captured before it was recorded
+    #[serde(default)]
+    pub(super) version: String,
     pub(super) code: u16,
     pub(super) message: String,
     pub(super) headers: MultiMap<String, String>,
+    // Content-Encoding the upstream sent the body with, the stored body is decoded
+    #[serde(default)]
+    pub(super) encoding: Option<String>,
+    // The decoded body was cut at the capture limit
+    #[serde(default)]
+    pub(super) truncated: bool,
     pub(super) body: SimpleBody,
 }
 
 impl Response {
+    pub fn version(&self) -> &String {
+        &self.version
+    }
+
     pub fn code(&self) -> u16 {
         self.code
     }
@@ -22,6 +35,14 @@ impl Response {
         &self.headers
     }
 
+    pub fn encoding(&self) -> Option<&String> {
+        self.encoding.as_ref()
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
     pub fn body(&self) -> &SimpleBody {
         &self.body
     }
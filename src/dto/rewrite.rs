@@ -0,0 +1,90 @@
+use crate::proxy::rewrite::{self, Scope, Target};
+
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RewriteTarget {
+    RequestLine,
+    RequestHeader,
+    RequestBody,
+    ResponseHeader,
+    ResponseBody,
+}
+
+// Match-and-replace rule as written in the rules file and the control api
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RewriteRule {
+    pub target: RewriteTarget,
+    #[serde(rename = "match", default)]
+    pub pattern: String,
+    #[serde(default)]
+    pub regex: bool,
+    #[serde(default)]
+    pub replace: String,
+    // Scope of the rule, everything matches when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+impl TryFrom<RewriteRule> for rewrite::RewriteRule {
+    type Error = String;
+
+    fn try_from(rule: RewriteRule) -> Result<Self, Self::Error> {
+        let scope = Scope {
+            host: rule.host.as_deref().map(str::parse).transpose()?,
+            path_prefix: rule.path,
+            method: rule
+                .method
+                .map(|method| {
+                    method
+                        .parse()
+                        .map_err(|_| format!("invalid method {:?}", method))
+                })
+                .transpose()?,
+            content_type: rule.content_type,
+        };
+        let target = match rule.target {
+            RewriteTarget::RequestLine => Target::RequestLine,
+            RewriteTarget::RequestHeader => Target::RequestHeader,
+            RewriteTarget::RequestBody => Target::RequestBody,
+            RewriteTarget::ResponseHeader => Target::ResponseHeader,
+            RewriteTarget::ResponseBody => Target::ResponseBody,
+        };
+        rewrite::RewriteRule::new(scope, target, rule.pattern, rule.regex, rule.replace)
+    }
+}
+
+impl From<&rewrite::RewriteRule> for RewriteRule {
+    fn from(rule: &rewrite::RewriteRule) -> Self {
+        RewriteRule {
+            target: match rule.target {
+                Target::RequestLine => RewriteTarget::RequestLine,
+                Target::RequestHeader => RewriteTarget::RequestHeader,
+                Target::RequestBody => RewriteTarget::RequestBody,
+                Target::ResponseHeader => RewriteTarget::ResponseHeader,
+                Target::ResponseBody => RewriteTarget::ResponseBody,
+            },
+            pattern: rule.pattern.clone(),
+            regex: rule.is_regex,
+            replace: rule.replacement.clone(),
+            host: rule.scope.host.as_ref().map(|host| host.to_string()),
+            path: rule.scope.path_prefix.clone(),
+            method: rule.scope.method.as_ref().map(|method| method.to_string()),
+            content_type: rule.scope.content_type.clone(),
+        }
+    }
+}
+
+// Parse rules written as a JSON array
+pub fn parse_rewrite_rules(json: &str) -> Result<Vec<rewrite::RewriteRule>, String> {
+    let rules: Vec<RewriteRule> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    rules
+        .into_iter()
+        .map(rewrite::RewriteRule::try_from)
+        .collect()
+}
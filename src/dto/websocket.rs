@@ -0,0 +1,39 @@
+use crate::proxy::websocket::{Direction, Frame};
+use std::time::UNIX_EPOCH;
+
+// WebSocket frame of the connection upgraded by the exchange `reqresp_id`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct WebSocketFrame {
+    pub id: String,
+    pub reqresp_id: String,
+    // "client" for frames sent by the client, "server" otherwise
+    pub direction: String,
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+    pub truncated: bool,
+    // Milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl From<Frame> for WebSocketFrame {
+    fn from(frame: Frame) -> Self {
+        WebSocketFrame {
+            id: String::new(),
+            reqresp_id: frame.exchange_id,
+            direction: match frame.direction {
+                Direction::ClientToServer => "client",
+                Direction::ServerToClient => "server",
+            }
+            .to_string(),
+            fin: frame.fin,
+            opcode: frame.opcode,
+            payload: frame.payload.to_vec(),
+            truncated: frame.truncated,
+            timestamp: frame
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::pin::Pin;
 
 pub mod api;
+pub mod ca;
 pub mod config;
 pub mod dto;
 pub mod proxy;
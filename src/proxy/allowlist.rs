@@ -0,0 +1,131 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ipnet::IpNet;
+use log::{debug, info};
+
+// Networks whose clients may connect to the proxy listeners, any client when empty.
+// Connections from elsewhere are closed right after they are accepted
+#[derive(Debug, Default)]
+pub struct ClientAllowlist {
+    networks: Vec<IpNet>,
+    rejected: AtomicU64,
+}
+
+impl ClientAllowlist {
+    pub fn new(networks: Vec<IpNet>) -> Self {
+        if !networks.is_empty() {
+            info!("Accepting clients only from {:?}", networks);
+        }
+        ClientAllowlist {
+            networks,
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    // Parse networks separated with ';', addresses without a prefix length are single hosts.
+    // Mapped IPv4 addresses are stored as IPv4, the way clients are checked
+    pub fn parse_networks(s: &str) -> Result<Vec<IpNet>, String> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse()
+                    .or_else(|_| {
+                        entry
+                            .parse::<IpAddr>()
+                            .map(|ip| IpNet::from(ip.to_canonical()))
+                    })
+                    .map_err(|_| format!("invalid network {:?}", entry))
+            })
+            .collect()
+    }
+
+    pub fn networks(&self) -> &[IpNet] {
+        &self.networks
+    }
+
+    // Check the client address, rejected clients are counted
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        // Dual stack listeners see IPv4 clients as mapped IPv6 addresses
+        let ip = ip.to_canonical();
+        if self.networks.is_empty() || self.networks.iter().any(|network| network.contains(&ip)) {
+            return true;
+        }
+        let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+        // Scanners may hit the port all the time, the counter is exposed by the control api
+        debug!(
+            "Rejected connection from {}, {} rejected in total",
+            ip, rejected
+        );
+        false
+    }
+
+    // Number of connections rejected since the start
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(s: &str) -> ClientAllowlist {
+        ClientAllowlist::new(ClientAllowlist::parse_networks(s).unwrap())
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks() {
+        let networks =
+            ClientAllowlist::parse_networks(" 10.0.0.0/8; 192.168.1.7 ;;fd00::/8").unwrap();
+        let expected: Vec<IpNet> = vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "192.168.1.7/32".parse().unwrap(),
+            "fd00::/8".parse().unwrap(),
+        ];
+        assert_eq!(networks, expected);
+        assert_eq!(
+            ClientAllowlist::parse_networks("::ffff:10.1.2.3").unwrap(),
+            ["10.1.2.3/32".parse::<IpNet>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        for s in ["10.0.0.0/33", "localhost", "10.0.0", "fd00::/129"] {
+            assert!(
+                ClientAllowlist::parse_networks(s).is_err(),
+                "{:?} is accepted",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn allows_everyone_when_empty() {
+        assert!(allowlist("").allows(ip("203.0.113.1")));
+    }
+
+    #[test]
+    fn checks_clients_and_counts_rejections() {
+        let allowlist = allowlist("10.0.0.0/8;192.168.1.7");
+        assert!(allowlist.allows(ip("10.20.30.40")));
+        assert!(allowlist.allows(ip("192.168.1.7")));
+        assert!(!allowlist.allows(ip("192.168.1.8")));
+        assert!(!allowlist.allows(ip("fd00::1")));
+        assert_eq!(allowlist.rejected(), 2);
+    }
+
+    #[test]
+    fn checks_mapped_ipv4_clients() {
+        let allowlist = allowlist("10.0.0.0/8");
+        assert!(allowlist.allows(ip("::ffff:10.0.0.1")));
+        assert!(!allowlist.allows(ip("::ffff:11.0.0.1")));
+    }
+}
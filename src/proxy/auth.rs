@@ -0,0 +1,144 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::BodyType;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderValue, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use subtle::ConstantTimeEq;
+
+// User allowed to use the proxy listener or the SOCKS5 one, written as "user:password"
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyCredentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl FromStr for ProxyCredentials {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, password) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| String::from("expected <user>:<password>"))?;
+        if user.is_empty() {
+            return Err(String::from("user must not be empty"));
+        }
+        Ok(ProxyCredentials {
+            user: user.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+impl ProxyCredentials {
+    // Compared in constant time, so timing does not tell how much of the password is right
+    pub fn matches(&self, user: &[u8], password: &[u8]) -> bool {
+        let user = self.user.as_bytes().ct_eq(user);
+        let password = self.password.as_bytes().ct_eq(password);
+        (user & password).into()
+    }
+}
+
+// The password is never printed
+impl fmt::Debug for ProxyCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyCredentials")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+// Clients of the proxy listener must send Basic Proxy-Authorization of one of the users
+#[derive(Clone, Debug, Default)]
+pub struct ProxyAuth {
+    pub users: Vec<ProxyCredentials>,
+}
+
+impl ProxyAuth {
+    // Parse users separated with ';'
+    pub fn parse_users(s: &str) -> Result<Vec<ProxyCredentials>, String> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    // User whose credentials the request carries, None if they are missing or wrong
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<String> {
+        let value = headers.get(header::PROXY_AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, token) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = BASE64_STANDARD.decode(token.trim()).ok()?;
+        let (user, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+        self.users
+            .iter()
+            .find(|credentials| credentials.matches(user.as_bytes(), password.as_bytes()))
+            .map(|credentials| credentials.user.clone())
+    }
+}
+
+pub fn authentication_required() -> Response<BodyType> {
+    let mut response = Response::new(
+        Full::new(Bytes::from("proxy authentication required"))
+            .map_err(|never| match never {})
+            .boxed(),
+    );
+    *response.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    response.headers_mut().insert(
+        header::PROXY_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"rusty-proxy\""),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(users: &str) -> ProxyAuth {
+        ProxyAuth {
+            users: ProxyAuth::parse_users(users).unwrap(),
+        }
+    }
+
+    fn headers(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::PROXY_AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    fn basic(credentials: &str) -> HeaderMap {
+        headers(&format!("Basic {}", BASE64_STANDARD.encode(credentials)))
+    }
+
+    #[test]
+    fn parses_users() {
+        let users = ProxyAuth::parse_users("alice:secret; bob:p:a:ss;").unwrap();
+        assert_eq!(users[0].user, "alice");
+        assert_eq!(users[1].password, "p:a:ss");
+        assert!(ProxyAuth::parse_users(":secret").is_err());
+        assert!(ProxyAuth::parse_users("alice").is_err());
+        assert!(!format!("{:?}", users[0]).contains("secret"));
+    }
+
+    #[test]
+    fn authenticates_basic_credentials() {
+        let auth = auth("alice:secret;bob:");
+        assert_eq!(
+            auth.authenticate(&basic("alice:secret")).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(auth.authenticate(&basic("bob:")).as_deref(), Some("bob"));
+        assert_eq!(auth.authenticate(&basic("alice:secre")), None);
+        assert_eq!(auth.authenticate(&basic("alice:secret2")), None);
+        assert_eq!(auth.authenticate(&basic("carol:secret")), None);
+        assert_eq!(auth.authenticate(&headers("Bearer token")), None);
+        assert_eq!(auth.authenticate(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,237 @@
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use super::BodyType;
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt};
+use http_body_util::{BodyExt, BodyStream, StreamBody};
+use hyper::body::{Body, Frame, SizeHint};
+use tokio::sync::oneshot;
+
+// Bodies are captured up to this size unless configured otherwise
+pub const DEFAULT_CAPTURE_LIMIT: usize = 10 * 1024 * 1024;
+
+// Details about the capture passed to the callback along with the exchange
+#[derive(Clone, Debug, Default)]
+pub struct CaptureInfo {
+    // Assigned by the proxy to link related records (e.g. WebSocket frames) to the exchange
+    pub id: String,
+    // A body was larger than the capture limit
+    pub truncated: bool,
+    // Authenticated user who made the exchange
+    pub user: Option<String>,
+    // Why the exchange failed: the upstream gave no response (the captured one is made
+    // by the proxy), the client connection timed out or a body was not transferred to the end
+    pub error: Option<String>,
+}
+
+// Exchange ids are object ids, so they can be used as storage keys as is
+pub fn new_exchange_id() -> String {
+    bson::oid::ObjectId::new().to_hex()
+}
+
+#[derive(Debug, Default)]
+pub struct CapturedBody {
+    pub bytes: Bytes,
+    // Only the first `limit` bytes were kept
+    pub truncated: bool,
+    // The body was read to the end, unset when it failed or was dropped midway
+    pub complete: bool,
+}
+
+// Wrap the body so it is forwarded as is while its first `limit` bytes are copied.
+// The copy is sent once the body ends or is dropped
+pub fn tee(inner: BodyType, limit: usize) -> (BodyType, oneshot::Receiver<CapturedBody>) {
+    let (done, captured) = oneshot::channel();
+    let body = TeeBody {
+        inner,
+        captured: BytesMut::new(),
+        limit,
+        truncated: false,
+        done: Some(done),
+    };
+    (BodyType::new(body), captured)
+}
+
+// Buffer the body if it is at most `limit` bytes long. A longer one is given back
+// as the part read so far followed by the rest, so it can still be forwarded
+pub async fn collect_limited(
+    mut body: BodyType,
+    limit: usize,
+) -> Result<Result<Bytes, BodyType>, hyper::Error> {
+    if body.size_hint().lower() > limit as u64 {
+        return Ok(Err(body));
+    }
+    let mut collected = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        if let Some(data) = frame?.data_ref() {
+            collected.extend_from_slice(data);
+        }
+        if collected.len() > limit {
+            let read = stream::iter([Ok(Frame::data(collected.freeze()))]);
+            let rest = BodyStream::new(body);
+            return Ok(Err(BodyType::new(StreamBody::new(read.chain(rest)))));
+        }
+    }
+    Ok(Ok(collected.freeze()))
+}
+
+struct TeeBody {
+    inner: BodyType,
+    captured: BytesMut,
+    limit: usize,
+    truncated: bool,
+    done: Option<oneshot::Sender<CapturedBody>>,
+}
+
+impl TeeBody {
+    fn capture(&mut self, data: &Bytes) {
+        let left = self.limit.saturating_sub(self.captured.len());
+        if data.len() > left {
+            self.truncated = true;
+        }
+        self.captured
+            .extend_from_slice(&data[..data.len().min(left)]);
+    }
+
+    fn finish(&mut self, complete: bool) {
+        if let Some(done) = self.done.take() {
+            let _ = done.send(CapturedBody {
+                bytes: self.captured.split().freeze(),
+                truncated: self.truncated,
+                complete,
+            });
+        }
+    }
+}
+
+impl Body for TeeBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    this.capture(data);
+                }
+                if this.inner.is_end_stream() {
+                    this.finish(true);
+                }
+            }
+            Some(Err(_)) => this.finish(false),
+            None => this.finish(true),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for TeeBody {
+    fn drop(&mut self) {
+        // Bodies with a known length may be dropped without polling the end of stream
+        let complete = self.inner.is_end_stream();
+        self.finish(complete);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Full;
+
+    fn full(data: &'static [u8]) -> BodyType {
+        Full::new(Bytes::from_static(data))
+            .map_err(|never| match never {})
+            .boxed()
+    }
+
+    fn chunked(chunks: &[&'static [u8]]) -> BodyType {
+        let frames = chunks
+            .iter()
+            .map(|chunk| Ok::<_, hyper::Error>(Frame::data(Bytes::from_static(chunk))))
+            .collect::<Vec<_>>();
+        BodyType::new(StreamBody::new(stream::iter(frames)))
+    }
+
+    #[tokio::test]
+    async fn captures_whole_body() {
+        let (body, captured) = tee(chunked(&[b"hello ", b"world"]), 1024);
+        let forwarded = body.collect().await.unwrap().to_bytes();
+        let captured = captured.await.unwrap();
+        assert_eq!(forwarded, "hello world");
+        assert_eq!(captured.bytes, "hello world");
+        assert!(!captured.truncated);
+        assert!(captured.complete);
+    }
+
+    #[tokio::test]
+    async fn cuts_body_at_limit() {
+        let (body, captured) = tee(chunked(&[b"hello ", b"world"]), 8);
+        let forwarded = body.collect().await.unwrap().to_bytes();
+        let captured = captured.await.unwrap();
+        assert_eq!(forwarded, "hello world");
+        assert_eq!(captured.bytes, "hello wo");
+        assert!(captured.truncated);
+        assert!(captured.complete);
+    }
+
+    #[tokio::test]
+    async fn body_of_exactly_limit_is_not_truncated() {
+        let (body, captured) = tee(full(b"hello"), 5);
+        body.collect().await.unwrap();
+        let captured = captured.await.unwrap();
+        assert_eq!(captured.bytes, "hello");
+        assert!(!captured.truncated);
+    }
+
+    #[tokio::test]
+    async fn unread_body_is_incomplete_but_not_truncated() {
+        let (body, captured) = tee(chunked(&[b"hello"]), 1024);
+        drop(body);
+        let captured = captured.await.unwrap();
+        assert!(captured.bytes.is_empty());
+        assert!(!captured.truncated);
+        assert!(!captured.complete);
+    }
+
+    #[tokio::test]
+    async fn dropped_body_with_known_length_is_complete() {
+        let (body, captured) = tee(BodyType::default(), 1024);
+        drop(body);
+        assert!(captured.await.unwrap().complete);
+    }
+
+    #[tokio::test]
+    async fn collects_bodies_within_limit() {
+        let collected = collect_limited(chunked(&[b"hello ", b"world"]), 11).await;
+        assert_eq!(collected.unwrap().unwrap(), "hello world");
+    }
+
+    #[tokio::test]
+    async fn gives_back_longer_bodies_whole() {
+        let body = match collect_limited(chunked(&[b"hello ", b"big ", b"world"]), 8).await {
+            Ok(Err(body)) => body,
+            _ => panic!("body over the limit is collected"),
+        };
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello big world");
+
+        let body = match collect_limited(full(b"hello world"), 8).await {
+            Ok(Err(body)) => body,
+            _ => panic!("body of known length over the limit is collected"),
+        };
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello world");
+    }
+}
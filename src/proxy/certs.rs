@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::ca::{CaError, CertificateAuthority};
+use log::{debug, error};
+use rustls::crypto::CryptoProvider;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+
+// Hosts whose certificates are kept, the least recently used one is dropped beyond that
+const MAX_CACHED_CERTIFICATES: usize = 1024;
+// Leaf certificates are valid for a year, they are reissued well before expiring
+const MAX_CERTIFICATE_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+struct Issued {
+    certified_key: Arc<CertifiedKey>,
+    issued_at: Instant,
+    last_used: Instant,
+}
+
+// Leaf certificates issued so far, shared between all intercepted connections
+pub struct CertificateCache {
+    ca: CertificateAuthority,
+    provider: Arc<CryptoProvider>,
+    issued: Mutex<HashMap<String, Issued>>,
+    capacity: usize,
+    max_age: Duration,
+}
+
+impl CertificateCache {
+    pub fn new(ca: CertificateAuthority, provider: Arc<CryptoProvider>) -> Self {
+        CertificateCache {
+            ca,
+            provider,
+            issued: Mutex::new(HashMap::new()),
+            capacity: MAX_CACHED_CERTIFICATES,
+            max_age: MAX_CERTIFICATE_AGE,
+        }
+    }
+
+    pub fn ca(&self) -> &CertificateAuthority {
+        &self.ca
+    }
+
+    pub fn get_or_issue(&self, host: &str) -> Result<Arc<CertifiedKey>, CaError> {
+        let host = host.to_ascii_lowercase();
+        if let Some(certified_key) = self.cached(&host) {
+            return Ok(certified_key);
+        }
+        // Issuing is slow, handshakes for other hosts must not wait for it
+        debug!("Issuing certificate for {}", host);
+        let certified_key = Arc::new(self.ca.issue(&host, &self.provider)?);
+
+        let now = Instant::now();
+        let mut issued = self.lock();
+        // Another handshake may have issued one meanwhile, it is kept so all of them match
+        if let Some(entry) = issued.get_mut(&host) {
+            if now.duration_since(entry.issued_at) < self.max_age {
+                entry.last_used = now;
+                return Ok(entry.certified_key.clone());
+            }
+        }
+        if issued.len() >= self.capacity && !issued.contains_key(&host) {
+            let least_recent = issued
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(host, _)| host.clone());
+            if let Some(least_recent) = least_recent {
+                issued.remove(&least_recent);
+            }
+        }
+        issued.insert(
+            host,
+            Issued {
+                certified_key: certified_key.clone(),
+                issued_at: now,
+                last_used: now,
+            },
+        );
+        Ok(certified_key)
+    }
+
+    fn cached(&self, host: &str) -> Option<Arc<CertifiedKey>> {
+        let now = Instant::now();
+        let mut issued = self.lock();
+        let entry = issued.get_mut(host)?;
+        if now.duration_since(entry.issued_at) >= self.max_age {
+            return None;
+        }
+        entry.last_used = now;
+        Some(entry.certified_key.clone())
+    }
+
+    // A poisoned cache only means that some thread panicked, the map itself is fine
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Issued>> {
+        self.issued
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Debug for CertificateCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertificateCache").finish_non_exhaustive()
+    }
+}
+
+// Picks a leaf certificate by SNI, falling back to the CONNECT authority
+#[derive(Debug)]
+pub struct HostCertResolver {
+    cache: Arc<CertificateCache>,
+    fallback_host: Option<String>,
+}
+
+impl HostCertResolver {
+    pub fn new(cache: Arc<CertificateCache>, fallback_host: Option<String>) -> Self {
+        HostCertResolver {
+            cache,
+            fallback_host,
+        }
+    }
+}
+
+impl ResolvesServerCert for HostCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let host = client_hello
+            .server_name()
+            .map(String::from)
+            .or_else(|| self.fallback_host.clone())?;
+        match self.cache.get_or_issue(&host) {
+            Ok(certified_key) => Some(certified_key),
+            Err(e) => {
+                error!("Failed to issue certificate for {}: {}", host, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(capacity: usize, max_age: Duration) -> CertificateCache {
+        let ca = CertificateAuthority::generate("Test Root").unwrap();
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        CertificateCache {
+            capacity,
+            max_age,
+            ..CertificateCache::new(ca, provider)
+        }
+    }
+
+    fn cached_hosts(cache: &CertificateCache) -> Vec<String> {
+        let mut hosts: Vec<String> = cache.lock().keys().cloned().collect();
+        hosts.sort();
+        hosts
+    }
+
+    #[test]
+    fn reuses_issued_certificates() {
+        let cache = cache(8, MAX_CERTIFICATE_AGE);
+        let first = cache.get_or_issue("example.com").unwrap();
+        let second = cache.get_or_issue("EXAMPLE.com").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = cache(2, MAX_CERTIFICATE_AGE);
+        cache.get_or_issue("a.example").unwrap();
+        cache.get_or_issue("b.example").unwrap();
+        // a is used again, so b is the least recently used one
+        cache.get_or_issue("a.example").unwrap();
+        cache.get_or_issue("c.example").unwrap();
+        assert_eq!(cached_hosts(&cache), ["a.example", "c.example"]);
+    }
+
+    #[test]
+    fn reissues_expired_certificates() {
+        let cache = cache(8, Duration::ZERO);
+        let first = cache.get_or_issue("example.com").unwrap();
+        let second = cache.get_or_issue("example.com").unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(cached_hosts(&cache), ["example.com"]);
+    }
+}
@@ -1,80 +1,341 @@
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo};
 use tokio::net::TcpStream;
 
+use super::host_pattern::HostPattern;
+use super::pool::{Pool, PoolKey, PoolSettings, Sender};
+use super::resolver::{Resolver, ResolverSettings};
+use super::timeouts::Timeouts;
+use super::upstream_proxy::{proxy_error, proxy_target, UpstreamProxy, UpstreamProxySettings};
+use super::upstream_tls::UpstreamTls;
 use super::BodyType;
-use http::{HeaderValue, Request, Response};
+use http::uri::PathAndQuery;
+use http::{header, HeaderValue, Request, Response, StatusCode, Uri, Version};
 use http_body_util::combinators::BoxBody;
 use hyper::client;
-use log::error;
+use hyper::rt::{Read, Write};
+use log::{debug, error, warn};
 use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use std::future::Future;
+use std::io;
 use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
 
+const ALPN_H2: &[u8] = b"h2";
+const ALPN_HTTP11: &[u8] = b"http/1.1";
+
+#[derive(Clone, Debug)]
+pub struct ClientSettings {
+    pub pool: PoolSettings,
+    // Offer HTTP/2 to TLS upstreams with ALPN
+    pub http2: bool,
+    // Plain text upstreams spoken to with HTTP/2 prior knowledge (h2c)
+    pub h2c_hosts: Vec<HostPattern>,
+    pub proxy: UpstreamProxySettings,
+    pub resolver: ResolverSettings,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        ClientSettings {
+            pool: PoolSettings::default(),
+            http2: true,
+            h2c_hosts: Vec::new(),
+            proxy: UpstreamProxySettings::default(),
+            resolver: ResolverSettings::default(),
+        }
+    }
+}
+
+// Failure to get a response from the upstream, answered to the client with 502 or 504
+#[derive(Error, Debug)]
+pub enum UpstreamError {
+    #[error("failed to resolve {host}: {source}")]
+    Dns { host: String, source: io::Error },
+
+    #[error("failed to connect to {target}: {source}")]
+    Connect { target: String, source: io::Error },
+
+    #[error("upstream proxy {proxy} failed: {source}")]
+    Proxy { proxy: String, source: io::Error },
+
+    #[error("invalid TLS server name {0:?}")]
+    ServerName(String),
+
+    #[error("TLS handshake with {host} failed: {source}")]
+    Tls { host: String, source: io::Error },
+
+    #[error("{stage} with {target} timed out after {after:?}")]
+    Timeout {
+        stage: &'static str,
+        target: String,
+        after: Duration,
+    },
+
+    #[error("HTTP exchange with the upstream failed: {0}")]
+    Http(#[from] hyper::Error),
+}
+
+impl UpstreamError {
+    pub fn status(&self) -> StatusCode {
+        let timed_out = match self {
+            UpstreamError::Connect { source, .. }
+            | UpstreamError::Proxy { source, .. }
+            | UpstreamError::Tls { source, .. } => source.kind() == io::ErrorKind::TimedOut,
+            UpstreamError::Http(e) => e.is_timeout(),
+            UpstreamError::Timeout { .. } => true,
+            UpstreamError::Dns { .. } | UpstreamError::ServerName(_) => false,
+        };
+        match timed_out {
+            true => StatusCode::GATEWAY_TIMEOUT,
+            false => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+// Upstream client, clones share the TLS settings and the connection pool
 #[derive(Clone)]
-pub struct Client {}
+pub struct Client {
+    tls: Arc<UpstreamTls>,
+    pool: Arc<Pool>,
+    h2c_hosts: Arc<Vec<HostPattern>>,
+    proxy: Arc<UpstreamProxySettings>,
+    resolver: Arc<Resolver>,
+    timeouts: Timeouts,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new(UpstreamTls::default(), ClientSettings::default())
+    }
+}
 
 impl Client {
+    pub fn new(tls: UpstreamTls, settings: ClientSettings) -> Self {
+        let alpn: &[&[u8]] = if settings.http2 {
+            &[ALPN_H2, ALPN_HTTP11]
+        } else {
+            &[ALPN_HTTP11]
+        };
+        Client {
+            tls: Arc::new(tls.with_alpn(alpn)),
+            pool: Arc::new(Pool::new(settings.pool)),
+            h2c_hosts: Arc::new(settings.h2c_hosts),
+            proxy: Arc::new(settings.proxy),
+            resolver: Arc::new(Resolver::new(settings.resolver)),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    // Limits for connecting, the TLS handshake and waiting for the response head
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    // Fail with a Timeout error once the limit passes
+    async fn limited<F: Future>(
+        limit: Option<Duration>,
+        stage: &'static str,
+        host: &str,
+        port: u16,
+        future: F,
+    ) -> Result<F::Output, UpstreamError> {
+        let Some(after) = limit else {
+            return Ok(future.await);
+        };
+        tokio::time::timeout(after, future).await.map_err(|_| {
+            warn!(
+                "{} with {}:{} timed out after {:?}",
+                stage, host, port, after
+            );
+            UpstreamError::Timeout {
+                stage,
+                target: format!("{}:{}", host, port),
+                after,
+            }
+        })
+    }
+
     pub async fn send_request(
+        &self,
         mut req: Request<BodyType>,
         host: String,
         port: u16,
         is_https: bool,
-    ) -> Result<Response<BodyType>, hyper::Error> {
-        if let Some(accept_encoding) = req.headers_mut().get_mut(http::header::ACCEPT_ENCODING) {
-            *accept_encoding = HeaderValue::from_str("").unwrap();
+    ) -> Result<Response<BodyType>, UpstreamError> {
+        let tls = is_https.then(|| self.tls.config_for(&host));
+        // Upgraded connections are taken over by the caller, so they are never pooled
+        let is_upgrade = req.headers().contains_key(header::UPGRADE);
+        // Plain requests are forwarded by HTTP proxies, upgrades and h2c need a CONNECT tunnel
+        let forward = match is_https || is_upgrade || self.is_h2c(&host) {
+            true => None,
+            false => self.proxy.forward_proxy_for(&host),
+        };
+        let key = match forward {
+            Some(proxy) => PoolKey::forward(&proxy.host, proxy.port),
+            None => PoolKey::new(&host, port, tls.as_ref()),
+        };
+        let target = proxy_target(&self.resolver, &host);
+        let prepare = |req, version| {
+            let mut req = prepare_request(req, version, &host, port, is_https);
+            if let Some(proxy) = forward {
+                proxy.prepare_forward(&mut req, &target, port);
+            }
+            req
+        };
+
+        if let Some(mut sender) = (!is_upgrade).then(|| self.pool.checkout(&key)).flatten() {
+            let prepared = prepare(req, sender.version());
+            let sent = sender.try_send_request(prepared);
+            match Client::limited(self.timeouts.response, "Response", &host, port, sent).await? {
+                Ok(resp) => {
+                    self.pool.checkin(key, sender);
+                    return Ok(resp.map(BoxBody::new));
+                }
+                Err(mut e) => match e.take_message() {
+                    // The pooled connection was closed before the request was sent
+                    Some(message) => {
+                        debug!(
+                            "Pooled connection to {}:{} is gone, reconnecting",
+                            host, port
+                        );
+                        req = message;
+                    }
+                    None => return Err(e.into_error().into()),
+                },
+            }
         }
-        if is_https {
-            Client::send_secure_request(req, host, port).await
-        } else {
-            Client::send_unsecure_request(req, host, port).await
+
+        let mut sender = match (tls, forward) {
+            (Some(config), _) => {
+                self.connect_secure(&host, port, config, !is_upgrade)
+                    .await?
+            }
+            (None, Some(proxy)) => self.connect_forward(proxy).await?,
+            (None, None) => self.connect_unsecure(&host, port, !is_upgrade).await?,
+        };
+        let req = prepare(req, sender.version());
+        let sent = sender.send_request(req);
+        let resp = Client::limited(self.timeouts.response, "Response", &host, port, sent).await??;
+        if !is_upgrade {
+            self.pool.checkin(key, sender);
         }
+
+        Ok(resp.map(BoxBody::new))
     }
 
-    async fn send_unsecure_request(
-        req: Request<BodyType>,
-        host: String,
+    // Raw TCP connection to the destination, through the upstream proxy when one is configured
+    pub async fn connect_tunnel(&self, host: &str, port: u16) -> Result<TcpStream, UpstreamError> {
+        let connected = self.proxy.connect(&self.resolver, host, port);
+        Client::limited(self.timeouts.connect, "Connection", host, port, connected).await?
+    }
+
+    fn is_h2c(&self, host: &str) -> bool {
+        self.h2c_hosts.iter().any(|pattern| pattern.matches(host))
+    }
+
+    // Connection to an HTTP proxy, which forwards plain requests to any host
+    async fn connect_forward(&self, proxy: &UpstreamProxy) -> Result<Sender, UpstreamError> {
+        let (host, port) = (proxy.host.as_str(), proxy.port);
+        let stream = Client::limited(
+            self.timeouts.connect,
+            "Connection",
+            host,
+            port,
+            proxy.open(),
+        )
+        .await?
+        .map_err(|source| proxy_error(proxy, source))?;
+        Ok(Client::handshake_http1(TokioIo::new(stream)).await?)
+    }
+
+    async fn connect_unsecure(
+        &self,
+        host: &str,
         port: u16,
-    ) -> Result<Response<BodyType>, hyper::Error> {
-        let stream = TcpStream::connect((host.as_str(), port)).await.unwrap();
+        allow_http2: bool,
+    ) -> Result<Sender, UpstreamError> {
+        let stream = self.connect_tunnel(host, port).await?;
         let io = TokioIo::new(stream);
+        let sender = if allow_http2 && self.is_h2c(host) {
+            Client::handshake_http2(io).await?
+        } else {
+            Client::handshake_http1(io).await?
+        };
+        Ok(sender)
+    }
+
+    async fn connect_secure(
+        &self,
+        host: &str,
+        port: u16,
+        mut config: Arc<ClientConfig>,
+        allow_http2: bool,
+    ) -> Result<Sender, UpstreamError> {
+        let stream = self.connect_tunnel(host, port).await?;
 
-        let (mut sender, conn) = client::conn::http1::Builder::new()
+        // Upgrades are defined only for HTTP/1.1
+        if !allow_http2 {
+            Arc::make_mut(&mut config).alpn_protocols = vec![ALPN_HTTP11.to_vec()];
+        }
+
+        let conn = tokio_rustls::TlsConnector::from(config);
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|_| UpstreamError::ServerName(host.to_string()))?;
+        let handshake = conn.connect(server_name, stream);
+        let io = Client::limited(
+            self.timeouts.tls_handshake,
+            "TLS handshake",
+            host,
+            port,
+            handshake,
+        )
+        .await?
+        .map_err(|source| UpstreamError::Tls {
+            host: host.to_string(),
+            source,
+        })?;
+        let is_http2 = io.get_ref().1.alpn_protocol() == Some(ALPN_H2);
+        debug!(
+            "Negotiated {} with {}:{}",
+            if is_http2 { "h2" } else { "http/1.1" },
+            host,
+            port
+        );
+        let sender = if is_http2 {
+            Client::handshake_http2(TokioIo::new(io)).await?
+        } else {
+            Client::handshake_http1(TokioIo::new(io)).await?
+        };
+        Ok(sender)
+    }
+
+    async fn handshake_http1<T>(io: T) -> Result<Sender, hyper::Error>
+    where
+        T: Read + Write + Unpin + Send + 'static,
+    {
+        let (sender, conn) = client::conn::http1::Builder::new()
             .preserve_header_case(true)
             .title_case_headers(true)
             .handshake(io)
             .await?;
 
         tokio::task::spawn(async move {
-            if let Err(err) = conn.await {
+            if let Err(err) = conn.with_upgrades().await {
                 error!("Connection failed: {:?}", err);
             }
         });
 
-        let resp = sender.send_request(req).await?;
-
-        Ok(resp.map(|b| BoxBody::new(b)))
+        Ok(Sender::Http1(sender))
     }
 
-    async fn send_secure_request(
-        req: Request<BodyType>,
-        host: String,
-        port: u16,
-    ) -> Result<Response<BodyType>, hyper::Error> {
-        let stream = TcpStream::connect((host.as_str(), port)).await.unwrap();
-
-        let root_store =
-            rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-        let config = rustls::ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-        let rc_config = Arc::new(config);
-        let conn = tokio_rustls::TlsConnector::from(rc_config);
-        let server_name = ServerName::try_from(host).unwrap();
-        let io = conn.connect(server_name, stream).await.unwrap();
-        let io = TokioIo::new(io);
-
-        let (mut sender, conn) = client::conn::http1::Builder::new()
-            .preserve_header_case(true)
-            .title_case_headers(true)
+    async fn handshake_http2<T>(io: T) -> Result<Sender, hyper::Error>
+    where
+        T: Read + Write + Unpin + Send + 'static,
+    {
+        let (sender, conn) = client::conn::http2::Builder::new(TokioExecutor::new())
             .handshake(io)
             .await?;
 
@@ -84,8 +345,51 @@ impl Client {
             }
         });
 
-        let resp = sender.send_request(req).await?;
+        Ok(Sender::Http2(sender))
+    }
+}
+
+// HTTP/1.1 upstreams get origin-form requests with a Host header,
+// HTTP/2 upstreams get absolute URIs which carry the authority instead
+fn prepare_request(
+    mut req: Request<BodyType>,
+    version: Version,
+    host: &str,
+    port: u16,
+    is_https: bool,
+) -> Request<BodyType> {
+    let authority = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+        .or_else(|| req.uri().authority().map(|a| a.to_string()))
+        .unwrap_or_else(|| format!("{}:{}", host, port));
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| PathAndQuery::from_static("/"));
 
-        Ok(resp.map(|b| BoxBody::new(b)))
+    let uri = if version == Version::HTTP_2 {
+        req.headers_mut().remove(header::HOST);
+        Uri::builder()
+            .scheme(if is_https { "https" } else { "http" })
+            .authority(authority.as_str())
+            .path_and_query(path_and_query)
+            .build()
+    } else {
+        if !req.headers().contains_key(header::HOST) {
+            if let Ok(value) = HeaderValue::from_str(&authority) {
+                req.headers_mut().insert(header::HOST, value);
+            }
+        }
+        Uri::builder().path_and_query(path_and_query).build()
+    };
+    match uri {
+        Ok(uri) => *req.uri_mut() = uri,
+        Err(e) => debug!("Could not rewrite request uri for {}: {}", authority, e),
     }
+    *req.version_mut() = version;
+    req
 }
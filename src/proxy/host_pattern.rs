@@ -0,0 +1,178 @@
+use regex::{Regex, RegexBuilder};
+use std::fmt;
+use std::str::FromStr;
+
+// Host matcher used by per-host settings.
+// Supported forms: "example.com" (exact), "*.example.com" (any subdomain), "*" (any host)
+// and "~<regex>" (regular expression matched against the whole host, case insensitive)
+#[derive(Clone, Debug)]
+pub enum HostPattern {
+    Any,
+    Exact(String),
+    Wildcard(String),
+    Regex(Regex),
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Exact(expected) => host.eq_ignore_ascii_case(expected),
+            HostPattern::Wildcard(suffix) => {
+                host.len() > suffix.len() + 1
+                    && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+                    && host.as_bytes()[host.len() - suffix.len() - 1] == b'.'
+            }
+            HostPattern::Regex(regex) => regex.is_match(host),
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().trim_end_matches('.');
+        if s.is_empty() {
+            return Err(String::from("empty host pattern"));
+        }
+        if s == "*" {
+            return Ok(HostPattern::Any);
+        }
+        if let Some(regex) = s.strip_prefix('~') {
+            return RegexBuilder::new(&format!("^(?:{})$", regex))
+                .case_insensitive(true)
+                .build()
+                .map(HostPattern::Regex)
+                .map_err(|e| format!("invalid host regex {:?}: {}", regex, e));
+        }
+        if let Some(suffix) = s.strip_prefix("*.") {
+            if suffix.is_empty() || suffix.contains('*') {
+                return Err(format!("invalid wildcard host pattern {:?}", s));
+            }
+            return Ok(HostPattern::Wildcard(suffix.to_ascii_lowercase()));
+        }
+        if s.contains('*') {
+            return Err(format!("wildcard is allowed only as first label: {:?}", s));
+        }
+        Ok(HostPattern::Exact(s.to_ascii_lowercase()))
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPattern::Any => write!(f, "*"),
+            HostPattern::Exact(host) => write!(f, "{}", host),
+            HostPattern::Wildcard(suffix) => write!(f, "*.{}", suffix),
+            HostPattern::Regex(regex) => {
+                let pattern = regex.as_str();
+                write!(f, "~{}", &pattern[4..pattern.len() - 2])
+            }
+        }
+    }
+}
+
+impl PartialEq for HostPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+impl Eq for HostPattern {}
+
+// Parse patterns separated by ';' (commas are left for regex quantifiers)
+pub fn parse_list(s: &str) -> Result<Vec<HostPattern>, String> {
+    s.split(';')
+        .filter(|pattern| !pattern.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// Find the value of the first pattern matching the host
+pub fn find_for_host<'a, T>(rules: &'a [(HostPattern, T)], host: &str) -> Option<&'a T> {
+    rules
+        .iter()
+        .find(|(pattern, _)| pattern.matches(host))
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> HostPattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_exact_hosts() {
+        let exact = pattern("Example.com");
+        assert!(exact.matches("example.com"));
+        assert!(exact.matches("EXAMPLE.COM."));
+        assert!(!exact.matches("www.example.com"));
+        assert!(!exact.matches("example.co"));
+    }
+
+    #[test]
+    fn matches_subdomains_with_wildcard() {
+        let wildcard = pattern("*.example.com");
+        assert!(wildcard.matches("www.example.com"));
+        assert!(wildcard.matches("a.b.Example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("badexample.com"));
+        assert!(pattern("*").matches("anything.test"));
+    }
+
+    #[test]
+    fn matches_whole_host_with_regex() {
+        let regex = pattern("~api[0-9]+\\.example\\.com");
+        assert!(regex.matches("API1.example.com"));
+        assert!(!regex.matches("api1.example.com.evil.test"));
+        assert!(!regex.matches("xapi1.example.com"));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for invalid in ["", " ", "*.*.example.com", "www.*.com", "~(unclosed"] {
+            assert!(invalid.parse::<HostPattern>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn displays_parsed_form() {
+        for s in ["*", "example.com", "*.example.com", "~api[0-9]{1,3}\\.test"] {
+            assert_eq!(pattern(s).to_string(), s);
+        }
+        assert_eq!(pattern(" Example.COM. ").to_string(), "example.com");
+    }
+
+    #[test]
+    fn parses_lists_separated_by_semicolons() {
+        let patterns = parse_list("grpc.local; *.h2c.internal;;~a{1,2}.test").unwrap();
+        assert_eq!(
+            patterns,
+            vec![
+                pattern("grpc.local"),
+                pattern("*.h2c.internal"),
+                pattern("~a{1,2}.test"),
+            ]
+        );
+        assert!(parse_list("").unwrap().is_empty());
+        assert!(parse_list("ok.test;www.*.test").is_err());
+    }
+
+    #[test]
+    fn finds_first_matching_rule() {
+        let rules = vec![
+            (pattern("api.example.com"), 1),
+            (pattern("*.example.com"), 2),
+            (pattern("*"), 3),
+        ];
+        assert_eq!(find_for_host(&rules, "api.example.com"), Some(&1));
+        assert_eq!(find_for_host(&rules, "www.example.com"), Some(&2));
+        assert_eq!(find_for_host(&rules, "other.test"), Some(&3));
+        assert_eq!(find_for_host(&rules[..2], "other.test"), None);
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use super::host_pattern::HostPattern;
+use super::utils::{request_host, set_content_length};
+use bytes::Bytes;
+use http::uri::PathAndQuery;
+use http::{header, request, response, HeaderMap, HeaderName, Method, StatusCode, Uri};
+use log::{debug, error, info};
+use tokio::sync::oneshot;
+
+// Unattended messages are released after this time unless configured otherwise
+pub const DEFAULT_INTERCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Request,
+    Response,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Request => write!(f, "request"),
+            Stage::Response => write!(f, "response"),
+        }
+    }
+}
+
+// Exchanges to hold, written as "<request|response|both>=[METHOD ][host pattern][/path prefix]",
+// e.g. "request=POST api.example.com/login" or "response=*.example.com"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterceptRule {
+    // Both stages when not set
+    pub stage: Option<Stage>,
+    pub method: Option<Method>,
+    pub host: Option<HostPattern>,
+    pub path_prefix: String,
+}
+
+impl InterceptRule {
+    fn matches(&self, stage: Stage, request: &request::Parts) -> bool {
+        self.stage.is_none_or(|expected| expected == stage)
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|method| *method == request.method)
+            && self.host.as_ref().is_none_or(|pattern| {
+                request_host(request).is_some_and(|host| pattern.matches(&host))
+            })
+            && request.uri.path().starts_with(&self.path_prefix)
+    }
+}
+
+impl FromStr for InterceptRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stage, matcher) = s
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| format!("expected <stage>=<matcher>, got {:?}", s))?;
+        let stage = match stage.trim() {
+            "request" => Some(Stage::Request),
+            "response" => Some(Stage::Response),
+            "both" => None,
+            stage => {
+                return Err(format!(
+                    "unknown stage {:?}, expected request, response or both",
+                    stage
+                ))
+            }
+        };
+        let matcher = matcher.trim();
+        let (method, matcher) = match matcher.split_once(' ') {
+            Some((method, matcher)) => (
+                Some(Method::from_str(method).map_err(|_| format!("invalid method {:?}", method))?),
+                matcher.trim(),
+            ),
+            None => (None, matcher),
+        };
+        let (host, path_prefix) = match matcher.find('/') {
+            Some(idx) => matcher.split_at(idx),
+            None => (matcher, ""),
+        };
+        let host = match host {
+            "" => None,
+            host => Some(host.parse()?),
+        };
+        Ok(InterceptRule {
+            stage,
+            method,
+            host,
+            path_prefix: path_prefix.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for InterceptRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.stage {
+            Some(stage) => write!(f, "{}=", stage)?,
+            None => write!(f, "both=")?,
+        }
+        if let Some(method) = &self.method {
+            write!(f, "{} ", method)?;
+        }
+        if let Some(host) = &self.host {
+            write!(f, "{}", host)?;
+        }
+        write!(f, "{}", self.path_prefix)
+    }
+}
+
+// What happens to messages nobody decided on in time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimeoutAction {
+    #[default]
+    Forward,
+    Drop,
+}
+
+impl FromStr for TimeoutAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "forward" => Ok(TimeoutAction::Forward),
+            "drop" => Ok(TimeoutAction::Drop),
+            s => Err(format!("unknown action {:?}, expected forward or drop", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InterceptSettings {
+    pub rules: Vec<InterceptRule>,
+    // Zero holds messages until a decision is made
+    pub timeout: Duration,
+    pub on_timeout: TimeoutAction,
+}
+
+impl Default for InterceptSettings {
+    fn default() -> Self {
+        InterceptSettings {
+            rules: Vec::new(),
+            timeout: DEFAULT_INTERCEPT_TIMEOUT,
+            on_timeout: TimeoutAction::default(),
+        }
+    }
+}
+
+impl InterceptSettings {
+    // Parse rules separated with ';'
+    pub fn parse_rules(s: &str) -> Result<Vec<InterceptRule>, String> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+// A request or response waiting for a decision, responses are held along with their request
+#[derive(Clone, Debug)]
+pub struct HeldMessage {
+    pub id: u64,
+    pub exchange_id: String,
+    pub stage: Stage,
+    pub is_https: bool,
+    pub request: request::Parts,
+    pub response: Option<response::Parts>,
+    // Body of the held message
+    pub body: Bytes,
+    pub held_at: SystemTime,
+}
+
+// Changes to the held message, fields which do not apply to its stage are ignored
+#[derive(Clone, Debug, Default)]
+pub struct Edit {
+    pub method: Option<Method>,
+    // Path with the query
+    pub path: Option<PathAndQuery>,
+    pub status: Option<StatusCode>,
+    // Replace all values of these headers
+    pub set_headers: HeaderMap,
+    pub remove_headers: Vec<HeaderName>,
+    pub body: Option<Bytes>,
+}
+
+impl Edit {
+    pub fn apply_to_request(self, parts: &mut request::Parts, body: &mut Bytes) {
+        if let Some(method) = self.method {
+            parts.method = method;
+        }
+        if let Some(path) = self.path {
+            let mut uri = parts.uri.clone().into_parts();
+            uri.path_and_query = Some(path);
+            match Uri::from_parts(uri) {
+                Ok(uri) => parts.uri = uri,
+                Err(e) => error!("Could not change path of the held request: {}", e),
+            }
+        }
+        // Headers set by the edit win over the ones derived from the new body
+        if let Some(new_body) = self.body {
+            set_body(&mut parts.headers, body, new_body);
+        }
+        edit_headers(&mut parts.headers, self.set_headers, self.remove_headers);
+    }
+
+    pub fn apply_to_response(self, parts: &mut response::Parts, body: &mut Bytes) {
+        if let Some(status) = self.status {
+            parts.status = status;
+        }
+        // Headers set by the edit win over the ones derived from the new body
+        if let Some(new_body) = self.body {
+            set_body(&mut parts.headers, body, new_body);
+        }
+        edit_headers(&mut parts.headers, self.set_headers, self.remove_headers);
+    }
+}
+
+#[derive(Debug)]
+pub enum Verdict {
+    Forward(Option<Box<Edit>>),
+    Drop,
+}
+
+// Messages matching the rules are held here until they are forwarded or dropped
+pub struct InterceptQueue {
+    settings: RwLock<InterceptSettings>,
+    next_id: AtomicU64,
+    held: Mutex<BTreeMap<u64, (HeldMessage, oneshot::Sender<Verdict>)>>,
+}
+
+impl InterceptQueue {
+    pub fn new(settings: InterceptSettings) -> Self {
+        InterceptQueue {
+            settings: RwLock::new(settings),
+            next_id: AtomicU64::new(1),
+            held: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn rules(&self) -> Vec<InterceptRule> {
+        self.settings.read().unwrap().rules.clone()
+    }
+
+    pub fn set_rules(&self, rules: Vec<InterceptRule>) {
+        info!("Intercept rules are replaced with {} rules", rules.len());
+        self.settings.write().unwrap().rules = rules;
+    }
+
+    pub fn matches(&self, stage: Stage, request: &request::Parts) -> bool {
+        self.settings
+            .read()
+            .unwrap()
+            .rules
+            .iter()
+            .any(|rule| rule.matches(stage, request))
+    }
+
+    // Held messages in the order they arrived
+    pub fn held(&self) -> Vec<HeldMessage> {
+        let held = self.held.lock().unwrap();
+        held.values().map(|(message, _)| message.clone()).collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<HeldMessage> {
+        let held = self.held.lock().unwrap();
+        held.get(&id).map(|(message, _)| message.clone())
+    }
+
+    // Release the held message, false if there is no such message anymore
+    pub fn resolve(&self, id: u64, verdict: Verdict) -> bool {
+        let entry = self.held.lock().unwrap().remove(&id);
+        match entry {
+            Some((_, decision)) => decision.send(verdict).is_ok(),
+            None => false,
+        }
+    }
+
+    // Wait until the message is released or the timeout passes
+    pub async fn hold(
+        &self,
+        stage: Stage,
+        exchange_id: &str,
+        is_https: bool,
+        request: &request::Parts,
+        response: Option<&response::Parts>,
+        body: Bytes,
+    ) -> Verdict {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (decision, decided) = oneshot::channel();
+        let message = HeldMessage {
+            id,
+            exchange_id: exchange_id.to_string(),
+            stage,
+            is_https,
+            request: request.clone(),
+            response: response.cloned(),
+            body,
+            held_at: SystemTime::now(),
+        };
+        self.held.lock().unwrap().insert(id, (message, decision));
+        debug!("Holding {} {} of exchange {}", stage, id, exchange_id);
+        // The message is removed if the client goes away while it is held
+        let _guard = HoldGuard { queue: self, id };
+
+        let (timeout, on_timeout) = {
+            let settings = self.settings.read().unwrap();
+            (settings.timeout, settings.on_timeout)
+        };
+        let verdict = if timeout.is_zero() {
+            decided.await.ok()
+        } else {
+            tokio::time::timeout(timeout, decided)
+                .await
+                .ok()
+                .and_then(Result::ok)
+        };
+        verdict.unwrap_or_else(|| {
+            info!("Held {} {} timed out, applying {:?}", stage, id, on_timeout);
+            match on_timeout {
+                TimeoutAction::Forward => Verdict::Forward(None),
+                TimeoutAction::Drop => Verdict::Drop,
+            }
+        })
+    }
+}
+
+struct HoldGuard<'a> {
+    queue: &'a InterceptQueue,
+    id: u64,
+}
+
+impl Drop for HoldGuard<'_> {
+    fn drop(&mut self) {
+        self.queue.held.lock().unwrap().remove(&self.id);
+    }
+}
+
+fn edit_headers(headers: &mut HeaderMap, set: HeaderMap, remove: Vec<HeaderName>) {
+    for name in remove.iter().chain(set.keys()) {
+        headers.remove(name);
+    }
+    for (name, value) in set.iter() {
+        headers.append(name, value.clone());
+    }
+}
+
+// The new body is sent as is, so the original Content-Encoding no longer applies
+fn set_body(headers: &mut HeaderMap, body: &mut Bytes, new_body: Bytes) {
+    headers.remove(header::CONTENT_ENCODING);
+    set_content_length(headers, new_body.len());
+    *body = new_body;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::utils::test_request;
+
+    fn rule(s: &str) -> InterceptRule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(
+            rule("request=POST api.example.com/login"),
+            InterceptRule {
+                stage: Some(Stage::Request),
+                method: Some(Method::POST),
+                host: Some("api.example.com".parse().unwrap()),
+                path_prefix: "/login".to_string(),
+            }
+        );
+        assert_eq!(
+            rule(" both = /admin "),
+            InterceptRule {
+                stage: None,
+                method: None,
+                host: None,
+                path_prefix: "/admin".to_string(),
+            }
+        );
+        for invalid in [
+            "*.example.com",
+            "always=/",
+            "request=G(T /",
+            "response=www.*.com",
+        ] {
+            assert!(invalid.parse::<InterceptRule>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn displays_parsable_rules() {
+        for s in [
+            "request=POST api.example.com/login",
+            "response=*.example.com",
+            "both=/admin",
+            "both=~api[0-9]+\\.test/v1",
+            "request=DELETE /",
+        ] {
+            assert_eq!(rule(s).to_string(), s);
+            assert_eq!(rule(&rule(s).to_string()), rule(s));
+        }
+    }
+
+    #[test]
+    fn matches_stage_method_host_and_path() {
+        let login = rule("request=POST *.example.com/login");
+        let post = test_request(Method::POST, "https://api.example.com/login?next=/");
+        assert!(login.matches(Stage::Request, &post));
+        assert!(!login.matches(Stage::Response, &post));
+        assert!(!login.matches(
+            Stage::Request,
+            &test_request(Method::GET, "https://api.example.com/login")
+        ));
+        assert!(!login.matches(
+            Stage::Request,
+            &test_request(Method::POST, "https://example.com/login")
+        ));
+        assert!(!login.matches(
+            Stage::Request,
+            &test_request(Method::POST, "https://api.example.com/")
+        ));
+        assert!(rule("both=").matches(Stage::Response, &post));
+    }
+
+    #[test]
+    fn edited_headers_win_over_body_headers() {
+        let (mut parts, ()) = http::Response::builder()
+            .header(header::CONTENT_ENCODING, "gzip")
+            .header(header::CONTENT_LENGTH, "3")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let mut body = Bytes::from_static(b"old");
+        let mut set_headers = HeaderMap::new();
+        set_headers.insert(header::CONTENT_ENCODING, "identity".parse().unwrap());
+        let edit = Edit {
+            set_headers,
+            body: Some(Bytes::from_static(b"plain body")),
+            ..Edit::default()
+        };
+        edit.apply_to_response(&mut parts, &mut body);
+        assert_eq!(body, "plain body");
+        assert_eq!(parts.headers[header::CONTENT_LENGTH], "10");
+        assert_eq!(parts.headers[header::CONTENT_ENCODING], "identity");
+    }
+
+    #[test]
+    fn new_body_drops_content_encoding() {
+        let mut parts = test_request(Method::POST, "http://example.com/");
+        parts
+            .headers
+            .insert(header::CONTENT_ENCODING, "gzip".parse().unwrap());
+        let mut body = Bytes::new();
+        let edit = Edit {
+            body: Some(Bytes::from_static(b"{}")),
+            ..Edit::default()
+        };
+        edit.apply_to_request(&mut parts, &mut body);
+        assert!(!parts.headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(parts.headers[header::CONTENT_LENGTH], "2");
+    }
+}
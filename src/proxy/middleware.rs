@@ -1,14 +1,29 @@
+use std::time::Duration;
 use std::{future::Future, pin::Pin, sync::Arc};
 
+use super::auth::{self, ProxyAuth};
+use super::certs::{CertificateCache, HostCertResolver};
+use super::client::Client;
+use super::passthrough::{self, PassthroughRules};
+use super::shutdown::Shutdown;
+use super::sniff;
+use super::timeouts::{self, ClientTimeout, IdleStream, Timeouts};
 use super::ProxyService;
 use bytes::Bytes;
-use http::{Method, Response};
+use http::{header, Method, Response};
 use http_body_util::{combinators::BoxBody, BodyExt, Empty};
-use hyper::server::conn::http1;
+use hyper::server::conn::{http1, http2};
 use hyper::{body::Incoming, service::Service, Request};
-use hyper_util::rt::TokioIo;
-use log::{debug, error};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use log::{debug, error, warn};
 use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+// Enough for a usual ClientHello, whose server name is looked for
+const SNIFF_LEN: usize = 4096;
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(1);
 
 // A TLS-connection upgrading service
 #[derive(Debug, Clone)]
@@ -16,16 +31,42 @@ pub struct TlsUpgrader<S> {
     inner_tls: S,
     inner: S,
     tls_config: ServerConfig,
+    certs: Arc<CertificateCache>,
+    passthrough: Arc<PassthroughRules>,
+    auth: Option<Arc<ProxyAuth>>,
+    timeouts: Timeouts,
 }
 
 impl<S> TlsUpgrader<S> {
-    pub fn new(inner: S, inner_tls: S, tls_config: ServerConfig) -> Self {
+    pub fn new(
+        inner: S,
+        inner_tls: S,
+        tls_config: ServerConfig,
+        certs: Arc<CertificateCache>,
+        passthrough: Arc<PassthroughRules>,
+    ) -> Self {
         TlsUpgrader {
             inner,
             inner_tls,
             tls_config,
+            certs,
+            passthrough,
+            auth: None,
+            timeouts: Timeouts::default(),
         }
     }
+
+    // Require Proxy-Authorization from clients of the proxy listener
+    pub fn with_auth(mut self, auth: Option<Arc<ProxyAuth>>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    // Limits for client TLS handshakes, request heads and idle connections
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
 }
 
 impl Service<Request<Incoming>> for TlsUpgrader<ProxyService> {
@@ -33,41 +74,283 @@ impl Service<Request<Incoming>> for TlsUpgrader<ProxyService> {
     type Error = hyper::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&self, req: Request<Incoming>) -> Self::Future {
+    fn call(&self, mut req: Request<Incoming>) -> Self::Future {
+        let user = match &self.auth {
+            Some(auth) => match auth.authenticate(req.headers()) {
+                Some(user) => {
+                    req.headers_mut().remove(header::PROXY_AUTHORIZATION);
+                    Some(user)
+                }
+                None => {
+                    debug!("Rejecting unauthenticated {} {}", req.method(), req.uri());
+                    return Box::pin(async { Ok(auth::authentication_required()) });
+                }
+            },
+            None => None,
+        };
+
         if req.method() == Method::CONNECT {
-            let config = Arc::new(self.tls_config.clone());
-            let tls_service = self.inner_tls.clone();
-            tokio::task::spawn(async move {
+            let connect_host = req
+                .uri()
+                .host()
+                .map(|host| host.trim_matches(|c| c == '[' || c == ']').to_string());
+            if let Some(host) = &connect_host {
+                if !self.passthrough.should_intercept(host) {
+                    return Box::pin(start_tunnel(
+                        req,
+                        self.inner.client.clone(),
+                        self.inner.shutdown.clone(),
+                    ));
+                }
+            }
+            let config = self.tls_config_for(connect_host);
+            let mut tls_service = self.inner_tls.clone();
+            // Requests inside the tunnel belong to the user who opened it
+            if user.is_some() {
+                tls_service.user = user;
+            }
+            let shutdown = tls_service.shutdown.clone();
+            let timeouts = self.timeouts;
+            shutdown.spawn(async move {
                 match hyper::upgrade::on(req).await {
                     Ok(upgraded) => {
                         debug!("Upgrading connection to TLS");
-                        let upgraded = TokioIo::new(upgraded);
-                        let tls_conn = tokio_rustls::TlsAcceptor::from(config);
-                        let stream = tls_conn.accept(upgraded).await.unwrap();
-                        let stream = TokioIo::new(stream);
-
-                        if let Err(err) = http1::Builder::new()
-                            .preserve_header_case(true)
-                            .title_case_headers(true)
-                            .serve_connection(stream, tls_service)
-                            .await
-                        {
-                            error!("Error serving connection: {err}");
-                        }
-                    }
-                    Err(e) => {
-                        error!("TLS upgrade error: {}", e);
-                        panic!("TLS upgrade error");
+                        serve_tls(TokioIo::new(upgraded), config, tls_service, timeouts).await;
                     }
+                    Err(e) => error!("TLS upgrade error: {}", e),
                 }
             });
-            return Box::pin(async { Ok(Response::new(empty_body())) });
+            Box::pin(async { Ok(Response::new(empty_body())) })
+        } else if user.is_some() {
+            ProxyService {
+                user,
+                ..self.inner.clone()
+            }
+            .call(req)
         } else {
             self.inner.call(req)
         }
     }
 }
 
+impl TlsUpgrader<ProxyService> {
+    // Exchanges of the served stream are recorded as made by the user
+    pub fn with_user(mut self, user: String) -> Self {
+        self.inner.user = Some(user.clone());
+        self.inner_tls.user = Some(user);
+        self
+    }
+
+    // Exchanges of the served connection record its timeout
+    pub fn with_client_timeout(mut self, timed_out: ClientTimeout) -> Self {
+        self.inner.client_timeout = timed_out.clone();
+        self.inner_tls.client_timeout = timed_out;
+        self
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.inner.client
+    }
+
+    // Serve a stream whose destination is already known (from a SOCKS or transparent listener).
+    // TLS is intercepted, plain HTTP is served directly and anything else is relayed as is,
+    // over the upstream connection when one is already open
+    pub async fn serve_stream(
+        mut self,
+        stream: TcpStream,
+        host: String,
+        port: u16,
+        upstream: Option<TcpStream>,
+    ) {
+        let head = match peek_head(&stream).await {
+            Ok(head) => head,
+            Err(e) => {
+                error!("Failed to read from {}:{} stream: {}", host, port, e);
+                return;
+            }
+        };
+        let head = &head[..];
+        let stream = IdleStream::new(stream, self.timeouts.idle);
+        self = self.with_client_timeout(stream.timed_out());
+        let target = Some((host.clone(), port));
+        self.inner.target = target.clone();
+        self.inner_tls.target = target;
+
+        // The destination may be an address, rules and certificates need the host name
+        let server_name = sniff::server_name(head).unwrap_or_else(|| host.clone());
+        let intercepted = match sniff::is_tls_handshake(head) {
+            true => self.passthrough.should_intercept(&server_name),
+            false => sniff::is_http_request(head),
+        };
+        // Intercepted exchanges are sent over the client connections, not the open one
+        let upstream = upstream.filter(|_| !intercepted);
+        if sniff::is_tls_handshake(head) && intercepted {
+            debug!(
+                "Intercepting TLS stream to {} ({}:{})",
+                server_name, host, port
+            );
+            let config = self.tls_config_for(Some(server_name));
+            serve_tls(stream, config, self.inner_tls, self.timeouts).await;
+        } else if sniff::is_http_request(head) {
+            debug!("Serving plain HTTP stream to {}:{}", host, port);
+            let shutdown = self.inner.shutdown.clone();
+            let conn = http1_builder(self.timeouts)
+                .serve_connection(TokioIo::new(stream), self.inner)
+                .with_upgrades();
+            if let Err(err) = shutdown.watch(conn).await {
+                report_connection_error(err);
+            }
+        } else {
+            let connected = match upstream {
+                Some(upstream) => Ok(upstream),
+                None => self.inner.client.connect_tunnel(&host, port).await,
+            };
+            let upstream = match connected {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    error!("Failed to open tunnel to {}:{}: {}", host, port, e);
+                    return;
+                }
+            };
+            passthrough::tunnel(stream, upstream, format!("{}:{}", host, port)).await;
+        }
+    }
+
+    // Serve a stream of origin-form requests, TLS is decrypted with a certificate for the SNI host
+    pub async fn serve_origin(mut self, stream: TcpStream) {
+        let head = match peek_head(&stream).await {
+            Ok(head) => head,
+            Err(e) => {
+                error!("Failed to read from stream: {}", e);
+                return;
+            }
+        };
+        let stream = IdleStream::new(stream, self.timeouts.idle);
+        self = self.with_client_timeout(stream.timed_out());
+        if sniff::is_tls_handshake(&head) {
+            let config = self.tls_config_for(sniff::server_name(&head));
+            serve_tls(stream, config, self.inner_tls, self.timeouts).await;
+        } else {
+            let shutdown = self.inner.shutdown.clone();
+            let conn = http1_builder(self.timeouts)
+                .serve_connection(TokioIo::new(stream), self.inner)
+                .with_upgrades();
+            if let Err(err) = shutdown.watch(conn).await {
+                report_connection_error(err);
+            }
+        }
+    }
+}
+
+// First bytes sent by the client, empty if it sends nothing for a while
+async fn peek_head(stream: &TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut head = vec![0u8; SNIFF_LEN];
+    // Protocols where the server speaks first send nothing
+    let n = match timeout(SNIFF_TIMEOUT, stream.peek(&mut head)).await {
+        Ok(n) => n?,
+        Err(_) => 0,
+    };
+    head.truncate(n);
+    Ok(head)
+}
+
+impl<S> TlsUpgrader<S> {
+    // The host is used when the client does not send SNI
+    fn tls_config_for(&self, host: Option<String>) -> Arc<ServerConfig> {
+        let mut config = self.tls_config.clone();
+        config.cert_resolver = Arc::new(HostCertResolver::new(self.certs.clone(), host));
+        Arc::new(config)
+    }
+}
+
+// Accept TLS from the client and serve the decrypted connection with the negotiated protocol
+async fn serve_tls<I>(io: I, config: Arc<ServerConfig>, service: ProxyService, limits: Timeouts)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let tls_conn = tokio_rustls::TlsAcceptor::from(config);
+    let stream = match timeouts::within(limits.tls_handshake, tls_conn.accept(io)).await {
+        Some(Ok(stream)) => stream,
+        Some(Err(e)) => {
+            error!("TLS handshake error: {}", e);
+            return;
+        }
+        None => {
+            warn!(
+                "Client TLS handshake timed out after {:?}",
+                limits.tls_handshake
+            );
+            return;
+        }
+    };
+    let is_http2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+    let stream = TokioIo::new(stream);
+    let shutdown = service.shutdown.clone();
+
+    let served = if is_http2 {
+        debug!("Serving decrypted connection with HTTP/2");
+        let conn = http2::Builder::new(TokioExecutor::new()).serve_connection(stream, service);
+        shutdown.watch(conn).await
+    } else {
+        let conn = http1_builder(limits)
+            .title_case_headers(true)
+            .serve_connection(stream, service)
+            .with_upgrades();
+        shutdown.watch(conn).await
+    };
+    if let Err(err) = served {
+        report_connection_error(err);
+    }
+}
+
+// HTTP/1 server dropping clients which do not send a request head in time
+pub(super) fn http1_builder(timeouts: Timeouts) -> http1::Builder {
+    let mut builder = http1::Builder::new();
+    builder
+        .preserve_header_case(true)
+        .timer(TokioTimer::new())
+        .header_read_timeout(timeouts.header_read);
+    builder
+}
+
+pub(super) fn report_connection_error(err: hyper::Error) {
+    if err.is_timeout() {
+        warn!("Closing client connection: {err}");
+    } else {
+        error!("Error serving connection: {err:?}");
+    }
+}
+
+// Connect to the CONNECT target and relay the tunnel without decrypting it
+async fn start_tunnel(
+    req: Request<Incoming>,
+    client: Client,
+    shutdown: Shutdown,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    // Safe unwrap since hyper rejects CONNECT requests without authority
+    let target = req.uri().authority().unwrap().clone();
+    let host = target.host().trim_matches(|c| c == '[' || c == ']');
+    let port = target.port_u16().unwrap_or(443);
+    let target = target.to_string();
+    let upstream = match client.connect_tunnel(host, port).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            error!("Failed to open tunnel to {}: {}", target, e);
+            let mut response = Response::new(empty_body());
+            *response.status_mut() = e.status();
+            return Ok(response);
+        }
+    };
+    debug!("Passing tunnel to {} through", target);
+    shutdown.spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => passthrough::tunnel(TokioIo::new(upgraded), upstream, target).await,
+            Err(e) => error!("Tunnel upgrade error: {}", e),
+        }
+    });
+    Ok(Response::new(empty_body()))
+}
+
 fn empty_body() -> BoxBody<Bytes, hyper::Error> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
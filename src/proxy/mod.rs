@@ -1,28 +1,81 @@
-use hyper::server::conn::http1;
+use crate::ca::CertificateAuthority;
+use allowlist::ClientAllowlist;
+use auth::{ProxyAuth, ProxyCredentials};
+use certs::{CertificateCache, HostCertResolver};
+use client::{Client, ClientSettings};
 use hyper_util::rt::TokioIo;
-use log::{error, info};
-use middleware::TlsUpgrader;
-use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use intercept::InterceptQueue;
+use log::{debug, error, info, warn};
+use middleware::{http1_builder, report_connection_error, TlsUpgrader};
+use passthrough::PassthroughRules;
+use reverse::ReverseProxySettings;
+use rewrite::Rewriter;
+use scope::CaptureScope;
+use shutdown::Shutdown;
 use std::net::SocketAddr;
-use tokio::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+use timeouts::{ClientTimeout, IdleStream, Timeouts};
+use tokio::net::{TcpListener, TcpStream};
+use upstream_tls::{UpstreamTls, UpstreamTlsError, UpstreamTlsSettings};
 
 use service::ProxyService;
 
 use thiserror::Error;
 
+pub mod allowlist;
+pub mod auth;
+pub mod capture;
+pub mod certs;
 pub mod client;
+pub mod host_pattern;
+pub mod intercept;
 mod middleware;
+mod onboarding;
+pub mod passthrough;
+pub mod pool;
+pub mod resolver;
+pub mod reverse;
+pub mod rewrite;
+pub mod scope;
 mod service;
+pub mod shutdown;
+mod sniff;
+pub mod socks;
+pub mod timeouts;
+pub mod transparent;
+pub mod upstream_proxy;
+pub mod upstream_tls;
 pub mod utils;
+pub mod websocket;
 
+pub use capture::CaptureInfo;
+pub use onboarding::MAGIC_HOST;
 pub use service::BodyType;
 pub use service::CallbackType;
+pub use websocket::FrameCallbackType;
 
 pub struct Proxy {
     addr: SocketAddr,
-    cert: String,
-    key: String,
+    ca: CertificateAuthority,
+    client: Client,
     callback: Option<service::CallbackType>,
+    frame_callback: Option<FrameCallbackType>,
+    capture_limit: usize,
+    scope: Arc<CaptureScope>,
+    passthrough: Arc<PassthroughRules>,
+    socks_addr: Option<SocketAddr>,
+    socks_credentials: Option<Arc<ProxyCredentials>>,
+    auth: Option<Arc<ProxyAuth>>,
+    allowlist: Arc<ClientAllowlist>,
+    transparent_addr: Option<SocketAddr>,
+    reverse_addr: Option<SocketAddr>,
+    reverse: Arc<ReverseProxySettings>,
+    intercept: Option<Arc<InterceptQueue>>,
+    rewriter: Option<Arc<Rewriter>>,
+    shutdown: Shutdown,
+    drain_timeout: Duration,
+    timeouts: Timeouts,
 }
 
 impl Proxy {
@@ -30,53 +83,230 @@ impl Proxy {
         ProxyBuilder::default()
     }
 
+    // Stops serve, may be triggered from anywhere
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
     pub async fn serve(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let listener = TcpListener::bind(self.addr).await?;
+        let socks_listener = match self.socks_addr {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        let transparent_listener = match self.transparent_addr {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        let reverse_listener = match self.reverse_addr {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
 
-        let certs = CertificateDer::pem_file_iter(self.cert)
-            .unwrap()
-            .map(|cert| cert.unwrap())
-            .collect();
-        let private_key = PrivateKeyDer::from_pem_file(self.key).unwrap();
-        let config = rustls::ServerConfig::builder()
+        let config_builder = rustls::ServerConfig::builder();
+        let certs = Arc::new(CertificateCache::new(
+            self.ca,
+            config_builder.crypto_provider().clone(),
+        ));
+        // The resolver is replaced for every CONNECT to know the requested authority
+        let mut config = config_builder
             .with_no_client_auth()
-            .with_single_cert(certs, private_key)
-            .unwrap();
+            .with_cert_resolver(Arc::new(HostCertResolver::new(certs.clone(), None)));
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
 
         info!("Listening on address {:?}", self.addr);
+        if let Some(addr) = self.socks_addr {
+            info!("Listening for SOCKS5 on address {:?}", addr);
+        }
+        if let Some(addr) = self.transparent_addr {
+            info!("Listening for redirected connections on address {:?}", addr);
+        }
+        if let Some(addr) = self.reverse_addr {
+            info!("Listening as reverse proxy on address {:?}", addr);
+        }
 
-        loop {
-            let stream = match listener.accept().await {
-                Ok((stream, _)) => stream,
-                Err(e) => {
-                    error!("Failed to accept connection: {:?}", e);
-                    continue;
-                }
+        let upgrader = |reverse: Option<Arc<ReverseProxySettings>>| {
+            let service = ProxyService {
+                is_tls: false,
+                callback: self.callback.clone(),
+                frame_callback: self.frame_callback.clone(),
+                certs: certs.clone(),
+                client: self.client.clone(),
+                capture_limit: self.capture_limit,
+                scope: self.scope.clone(),
+                target: None,
+                reverse,
+                intercept: self.intercept.clone(),
+                rewriter: self.rewriter.clone(),
+                user: None,
+                shutdown: self.shutdown.clone(),
+                client_timeout: ClientTimeout::default(),
             };
-            let io = TokioIo::new(stream);
-
-            let service = TlsUpgrader::new(
-                ProxyService {
-                    is_tls: false,
-                    callback: self.callback.clone(),
-                },
+            TlsUpgrader::new(
+                service.clone(),
                 ProxyService {
                     is_tls: true,
-                    callback: self.callback.clone(),
+                    ..service
                 },
                 config.clone(),
-            );
-            tokio::task::spawn(async move {
-                if let Err(err) = http1::Builder::new()
-                    .preserve_header_case(true)
-                    .serve_connection(io, service)
-                    .with_upgrades()
-                    .await
-                {
-                    error!("Error serving connection: {err:?}");
+                certs.clone(),
+                self.passthrough.clone(),
+            )
+            .with_timeouts(self.timeouts)
+        };
+
+        let shutdown = self.shutdown.clone();
+        loop {
+            tokio::select! {
+                _ = shutdown.triggered() => break,
+                accepted = listener.accept() => {
+                    let stream = match accepted {
+                        Ok((stream, peer)) if self.allowlist.allows(peer.ip()) => stream,
+                        // Dropping the stream closes the connection
+                        Ok(_) => continue,
+                        Err(e) => {
+                            error!("Failed to accept connection: {:?}", e);
+                            continue;
+                        }
+                    };
+                    // Tunnels and TLS connections opened with CONNECT keep the idle limit
+                    let stream = IdleStream::new(stream, self.timeouts.idle);
+                    let service = upgrader(None)
+                        .with_auth(self.auth.clone())
+                        .with_client_timeout(stream.timed_out());
+                    let io = TokioIo::new(stream);
+                    let connection_shutdown = shutdown.clone();
+                    let timeouts = self.timeouts;
+                    shutdown.spawn(async move {
+                        let conn = http1_builder(timeouts)
+                            .serve_connection(io, service)
+                            .with_upgrades();
+                        if let Err(err) = connection_shutdown.watch(conn).await {
+                            report_connection_error(err);
+                        }
+                    });
+                }
+                accepted = accept_optional(&socks_listener) => {
+                    let stream = match accepted {
+                        Ok((stream, peer)) if self.allowlist.allows(peer.ip()) => stream,
+                        Ok(_) => continue,
+                        Err(e) => {
+                            error!("Failed to accept SOCKS connection: {:?}", e);
+                            continue;
+                        }
+                    };
+                    shutdown.spawn(serve_socks(
+                        stream,
+                        self.socks_credentials.clone(),
+                        upgrader(None),
+                    ));
+                }
+                accepted = accept_optional(&transparent_listener) => {
+                    let stream = match accepted {
+                        Ok((stream, peer)) if self.allowlist.allows(peer.ip()) => stream,
+                        Ok(_) => continue,
+                        Err(e) => {
+                            error!("Failed to accept redirected connection: {:?}", e);
+                            continue;
+                        }
+                    };
+                    shutdown.spawn(serve_transparent(stream, upgrader(None)));
                 }
-            });
+                accepted = accept_optional(&reverse_listener) => {
+                    let stream = match accepted {
+                        Ok((stream, peer)) if self.allowlist.allows(peer.ip()) => stream,
+                        Ok(_) => continue,
+                        Err(e) => {
+                            error!("Failed to accept reverse proxy connection: {:?}", e);
+                            continue;
+                        }
+                    };
+                    let upgrader = upgrader(Some(self.reverse.clone()));
+                    shutdown.spawn(upgrader.serve_origin(stream));
+                }
+            }
         }
+
+        // Closing the listeners refuses new connections while the rest is drained
+        drop(listener);
+        drop(socks_listener);
+        drop(transparent_listener);
+        drop(reverse_listener);
+        info!(
+            "Shutting down, waiting up to {:?} for {} tasks",
+            self.drain_timeout,
+            shutdown.pending()
+        );
+        match shutdown.drain(self.drain_timeout).await {
+            true => info!("All connections are finished"),
+            false => warn!(
+                "{} tasks are still running after {:?}, exiting anyway",
+                shutdown.pending(),
+                self.drain_timeout
+            ),
+        }
+        Ok(())
+    }
+}
+
+// Never resolves when the listener is disabled
+async fn accept_optional(
+    listener: &Option<TcpListener>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn serve_socks(
+    mut stream: TcpStream,
+    credentials: Option<Arc<ProxyCredentials>>,
+    upgrader: TlsUpgrader<ProxyService>,
+) {
+    let (host, port) = match socks::handshake(&mut stream, credentials.as_deref()).await {
+        Ok(destination) => destination,
+        Err(e) => {
+            error!("SOCKS handshake failed: {}", e);
+            return;
+        }
+    };
+    // The client is told whether the destination is reachable, so it is connected first
+    let upstream = match upgrader.client().connect_tunnel(&host, port).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            error!(
+                "Failed to connect to {}:{} for SOCKS client: {}",
+                host, port, e
+            );
+            if let Err(e) = socks::reply_failed(&mut stream, &e).await {
+                debug!("Failed to send SOCKS reply: {}", e);
+            }
+            return;
+        }
+    };
+    if let Err(e) = socks::reply_connected(&mut stream).await {
+        error!("Failed to send SOCKS reply: {}", e);
+        return;
+    }
+    let upgrader = match credentials {
+        Some(credentials) => upgrader.with_user(credentials.user.clone()),
+        None => upgrader,
+    };
+    upgrader
+        .serve_stream(stream, host, port, Some(upstream))
+        .await
+}
+
+async fn serve_transparent(stream: TcpStream, upgrader: TlsUpgrader<ProxyService>) {
+    match transparent::original_destination(&stream) {
+        Ok(destination) => {
+            let host = destination.ip().to_string();
+            upgrader
+                .serve_stream(stream, host, destination.port(), None)
+                .await
+        }
+        Err(e) => error!("Failed to get original destination: {}", e),
     }
 }
 
@@ -85,9 +315,26 @@ pub struct ProxyBuilder {
     host: Option<String>,
     port: Option<u16>,
     addr: Option<SocketAddr>,
-    cert_filepath: Option<String>,
-    key_filepath: Option<String>,
+    ca: Option<CertificateAuthority>,
+    upstream_tls: UpstreamTlsSettings,
+    client: ClientSettings,
     callback: Option<service::CallbackType>,
+    frame_callback: Option<FrameCallbackType>,
+    capture_limit: Option<usize>,
+    scope: CaptureScope,
+    passthrough: PassthroughRules,
+    socks_port: Option<u16>,
+    socks_credentials: Option<ProxyCredentials>,
+    auth: Option<ProxyAuth>,
+    allowlist: Option<Arc<ClientAllowlist>>,
+    transparent_port: Option<u16>,
+    reverse_port: Option<u16>,
+    reverse: ReverseProxySettings,
+    intercept: Option<Arc<InterceptQueue>>,
+    rewriter: Option<Arc<Rewriter>>,
+    shutdown: Option<Shutdown>,
+    drain_timeout: Option<Duration>,
+    timeouts: Timeouts,
 }
 
 impl ProxyBuilder {
@@ -110,9 +357,18 @@ impl ProxyBuilder {
         self
     }
 
-    pub fn with_tls(mut self, cert_path: String, key_path: String) -> ProxyBuilder {
-        self.cert_filepath = Some(cert_path);
-        self.key_filepath = Some(key_path);
+    pub fn with_tls(mut self, ca: CertificateAuthority) -> ProxyBuilder {
+        self.ca = Some(ca);
+        self
+    }
+
+    pub fn with_upstream_tls(mut self, settings: UpstreamTlsSettings) -> ProxyBuilder {
+        self.upstream_tls = settings;
+        self
+    }
+
+    pub fn with_client(mut self, settings: ClientSettings) -> ProxyBuilder {
+        self.client = settings;
         self
     }
 
@@ -121,12 +377,108 @@ impl ProxyBuilder {
         self
     }
 
+    // CONNECT tunnels to hosts excluded by the rules are relayed without decryption
+    pub fn with_passthrough(mut self, rules: PassthroughRules) -> ProxyBuilder {
+        self.passthrough = rules;
+        self
+    }
+
+    // Called for every frame of relayed WebSocket connections
+    pub fn with_frame_callback(mut self, callback: FrameCallbackType) -> ProxyBuilder {
+        self.frame_callback = Some(callback);
+        self
+    }
+
+    // Maximum number of bytes of each body passed to the callback
+    pub fn with_capture_limit(mut self, limit: usize) -> ProxyBuilder {
+        self.capture_limit = Some(limit);
+        self
+    }
+
+    // Only exchanges in the scope are passed to the callbacks, the rest is proxied unrecorded
+    pub fn with_capture_scope(mut self, scope: CaptureScope) -> ProxyBuilder {
+        self.scope = scope;
+        self
+    }
+
+    // Accept SOCKS5 clients on this port of the proxy host as well
+    pub fn with_socks_port(mut self, port: u16) -> ProxyBuilder {
+        self.socks_port = Some(port);
+        self
+    }
+
+    // Require username/password authentication from SOCKS5 clients
+    pub fn with_socks_credentials(mut self, credentials: ProxyCredentials) -> ProxyBuilder {
+        self.socks_credentials = Some(credentials);
+        self
+    }
+
+    // Require Proxy-Authorization of one of the users from clients of the proxy port
+    pub fn with_auth(mut self, auth: ProxyAuth) -> ProxyBuilder {
+        self.auth = Some(auth);
+        self
+    }
+
+    // Close connections from clients outside the allowlist on every listener
+    pub fn with_allowlist(mut self, allowlist: Arc<ClientAllowlist>) -> ProxyBuilder {
+        self.allowlist = Some(allowlist);
+        self
+    }
+
+    // Accept connections redirected by iptables on this port of the proxy host
+    pub fn with_transparent_port(mut self, port: u16) -> ProxyBuilder {
+        self.transparent_port = Some(port);
+        self
+    }
+
+    // Accept requests for the reverse proxy routes on this port of the proxy host
+    pub fn with_reverse_port(mut self, port: u16) -> ProxyBuilder {
+        self.reverse_port = Some(port);
+        self
+    }
+
+    pub fn with_reverse(mut self, settings: ReverseProxySettings) -> ProxyBuilder {
+        self.reverse = settings;
+        self
+    }
+
+    // Hold exchanges matching the queue rules until they are released through the queue
+    pub fn with_intercept(mut self, queue: Arc<InterceptQueue>) -> ProxyBuilder {
+        self.intercept = Some(queue);
+        self
+    }
+
+    // Rewrite exchanges with the rules of the rewriter, which may be replaced while running
+    pub fn with_rewriter(mut self, rewriter: Arc<Rewriter>) -> ProxyBuilder {
+        self.rewriter = Some(rewriter);
+        self
+    }
+
+    // Use the handle to stop the proxy, e.g. one shared with tasks spawned from the callbacks
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> ProxyBuilder {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    // How long to wait for connections and spawned tasks once shutdown is triggered
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> ProxyBuilder {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+
+    // Limits for upstream connections and responses as well as client handshakes,
+    // request heads and idle client connections
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> ProxyBuilder {
+        self.timeouts = timeouts;
+        self
+    }
+
     pub fn build(mut self) -> Result<Proxy, BuildError> {
-        if None == self.addr {
-            if None == self.host {
+        if self.addr.is_none() {
+            if self.host.is_none() {
                 return Err(BuildError::NoHost);
             };
-            if None == self.port {
+            if self.port.is_none() {
                 return Err(BuildError::NoPort);
             }
             let host = match self.host.unwrap().parse() {
@@ -137,15 +489,41 @@ impl ProxyBuilder {
             self.addr = Some(SocketAddr::new(host, port));
         }
 
-        if None == self.cert_filepath || None == self.key_filepath {
+        let Some(ca) = self.ca else {
             return Err(BuildError::NoSSL);
-        }
+        };
 
+        let client = Client::new(UpstreamTls::new(&self.upstream_tls)?, self.client)
+            .with_timeouts(self.timeouts);
+
+        let addr = self.addr.unwrap();
         Ok(Proxy {
-            addr: self.addr.unwrap(),
-            cert: self.cert_filepath.unwrap(),
-            key: self.key_filepath.unwrap(),
+            addr,
+            ca,
+            client,
             callback: self.callback,
+            frame_callback: self.frame_callback,
+            capture_limit: self.capture_limit.unwrap_or(capture::DEFAULT_CAPTURE_LIMIT),
+            scope: Arc::new(self.scope),
+            passthrough: Arc::new(self.passthrough),
+            socks_addr: self.socks_port.map(|port| SocketAddr::new(addr.ip(), port)),
+            socks_credentials: self.socks_credentials.map(Arc::new),
+            auth: self.auth.map(Arc::new),
+            allowlist: self.allowlist.unwrap_or_default(),
+            transparent_addr: self
+                .transparent_port
+                .map(|port| SocketAddr::new(addr.ip(), port)),
+            reverse_addr: self
+                .reverse_port
+                .map(|port| SocketAddr::new(addr.ip(), port)),
+            reverse: Arc::new(self.reverse),
+            intercept: self.intercept,
+            rewriter: self.rewriter,
+            shutdown: self.shutdown.unwrap_or_default(),
+            drain_timeout: self
+                .drain_timeout
+                .unwrap_or(shutdown::DEFAULT_DRAIN_TIMEOUT),
+            timeouts: self.timeouts,
         })
     }
 }
@@ -161,6 +539,9 @@ pub enum BuildError {
     #[error("connection port is not specified")]
     NoPort,
 
-    #[error("not given ssl certificates")]
+    #[error("not given certificate authority")]
     NoSSL,
+
+    #[error("invalid upstream tls settings: {0}")]
+    UpstreamTls(#[from] UpstreamTlsError),
 }
@@ -0,0 +1,81 @@
+use super::BodyType;
+use crate::ca::CertificateAuthority;
+use bytes::Bytes;
+use http::{header, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+
+// Requests to this host are answered by the proxy itself and never forwarded
+pub const MAGIC_HOST: &str = "rusty.proxy";
+
+const CA_PEM_PATH: &str = "/ca.pem";
+const CA_DER_PATH: &str = "/ca.der";
+
+const INDEX_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Rusty Proxy</title></head>
+<body>
+<h1>Rusty Proxy</h1>
+<p>Install the root certificate of this proxy to intercept HTTPS traffic.</p>
+<ul>
+<li><a href="/ca.pem">PEM certificate</a> (Firefox, Linux)</li>
+<li><a href="/ca.der">DER certificate</a> (Windows, macOS, Android, iOS)</li>
+</ul>
+</body>
+</html>
+"#;
+
+pub fn is_magic_host(host: &str) -> bool {
+    host.eq_ignore_ascii_case(MAGIC_HOST)
+}
+
+// Answer a request made to the magic host
+pub fn respond(path: &str, ca: &CertificateAuthority) -> Response<BodyType> {
+    match path {
+        "/" => build_response(
+            StatusCode::OK,
+            "text/html; charset=utf-8",
+            None,
+            Bytes::from_static(INDEX_PAGE.as_bytes()),
+        ),
+        CA_PEM_PATH => build_response(
+            StatusCode::OK,
+            "application/x-pem-file",
+            Some("rusty_proxy_ca.pem"),
+            Bytes::from(ca.cert_pem().to_string()),
+        ),
+        CA_DER_PATH => build_response(
+            StatusCode::OK,
+            "application/x-x509-ca-cert",
+            Some("rusty_proxy_ca.der"),
+            Bytes::from(ca.cert().to_vec()),
+        ),
+        _ => build_response(
+            StatusCode::NOT_FOUND,
+            "text/plain; charset=utf-8",
+            None,
+            Bytes::from_static(b"not found"),
+        ),
+    }
+}
+
+fn build_response(
+    status: StatusCode,
+    content_type: &str,
+    filename: Option<&str>,
+    body: Bytes,
+) -> Response<BodyType> {
+    let mut response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body.len());
+    if let Some(filename) = filename {
+        response = response.header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        );
+    }
+    // Safe unwrap since all headers are valid
+    response
+        .body(Full::new(body).map_err(|never| match never {}).boxed())
+        .unwrap()
+}
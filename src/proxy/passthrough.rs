@@ -0,0 +1,86 @@
+use std::time::Instant;
+
+use super::host_pattern::HostPattern;
+use log::{error, info};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+// Decides which CONNECT tunnels are decrypted. Denied hosts and, when the allowlist is set,
+// hosts missing from it are relayed without interception
+#[derive(Clone, Debug, Default)]
+pub struct PassthroughRules {
+    pub intercept: Vec<HostPattern>,
+    pub passthrough: Vec<HostPattern>,
+}
+
+impl PassthroughRules {
+    pub fn should_intercept(&self, host: &str) -> bool {
+        if self.passthrough.iter().any(|pattern| pattern.matches(host)) {
+            return false;
+        }
+        self.intercept.is_empty() || self.intercept.iter().any(|pattern| pattern.matches(host))
+    }
+}
+
+// Relay the tunnel byte-for-byte until both sides are closed
+pub async fn tunnel<T>(mut client: T, mut upstream: TcpStream, target: String)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let started = Instant::now();
+    match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+        Ok((sent, received)) => info!(
+            "Tunnel to {} closed after {:?}, {} bytes sent, {} bytes received",
+            target,
+            started.elapsed(),
+            sent,
+            received
+        ),
+        Err(e) => error!(
+            "Tunnel to {} failed after {:?}: {}",
+            target,
+            started.elapsed(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::host_pattern;
+
+    fn rules(intercept: &str, passthrough: &str) -> PassthroughRules {
+        PassthroughRules {
+            intercept: host_pattern::parse_list(intercept).unwrap(),
+            passthrough: host_pattern::parse_list(passthrough).unwrap(),
+        }
+    }
+
+    #[test]
+    fn intercepts_everything_by_default() {
+        assert!(PassthroughRules::default().should_intercept("example.com"));
+    }
+
+    #[test]
+    fn passes_denied_hosts_through() {
+        let rules = rules("", "*.bank.com;pinned.app");
+        assert!(!rules.should_intercept("online.bank.com"));
+        assert!(!rules.should_intercept("pinned.app"));
+        assert!(rules.should_intercept("example.com"));
+    }
+
+    #[test]
+    fn intercepts_only_allowed_hosts() {
+        let rules = rules("*.example.com", "");
+        assert!(rules.should_intercept("api.example.com"));
+        assert!(!rules.should_intercept("example.org"));
+    }
+
+    #[test]
+    fn passthrough_wins_over_intercept() {
+        let rules = rules("*.example.com", "login.example.com");
+        assert!(rules.should_intercept("api.example.com"));
+        assert!(!rules.should_intercept("login.example.com"));
+    }
+}
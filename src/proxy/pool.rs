@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use super::BodyType;
+use http::{Request, Response, Version};
+use hyper::body::Incoming;
+use hyper::client::conn::{http1, http2, TrySendError};
+use log::debug;
+use rustls::ClientConfig;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PoolSettings {
+    // Idle connections are closed after this time
+    pub idle_timeout: Duration,
+    // Connections above this limit are closed instead of going idle
+    pub max_idle_per_host: usize,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        PoolSettings {
+            idle_timeout: Duration::from_secs(90),
+            max_idle_per_host: 8,
+        }
+    }
+}
+
+// Upstream connections can be reused only with the same destination and TLS settings
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    is_https: bool,
+    host: String,
+    port: u16,
+    // Address of the client config, configs live as long as the client
+    tls: usize,
+    // Connection to an HTTP proxy shared by plain requests to any host
+    forward: bool,
+}
+
+impl PoolKey {
+    pub fn new(host: &str, port: u16, tls: Option<&Arc<ClientConfig>>) -> Self {
+        PoolKey {
+            is_https: tls.is_some(),
+            host: host.to_ascii_lowercase(),
+            port,
+            tls: tls.map_or(0, |config| Arc::as_ptr(config) as usize),
+            forward: false,
+        }
+    }
+
+    pub fn forward(proxy_host: &str, proxy_port: u16) -> Self {
+        PoolKey {
+            forward: true,
+            ..PoolKey::new(proxy_host, proxy_port, None)
+        }
+    }
+}
+
+// Sending half of an upstream connection
+pub enum Sender {
+    Http1(http1::SendRequest<BodyType>),
+    // HTTP/2 connections are multiplexed, so the sender is shared instead of taken out of the pool
+    Http2(http2::SendRequest<BodyType>),
+}
+
+impl Sender {
+    pub fn version(&self) -> Version {
+        match self {
+            Sender::Http1(_) => Version::HTTP_11,
+            Sender::Http2(_) => Version::HTTP_2,
+        }
+    }
+
+    pub async fn send_request(
+        &mut self,
+        req: Request<BodyType>,
+    ) -> Result<Response<Incoming>, hyper::Error> {
+        match self {
+            Sender::Http1(sender) => sender.send_request(req).await,
+            Sender::Http2(sender) => sender.send_request(req).await,
+        }
+    }
+
+    pub async fn try_send_request(
+        &mut self,
+        req: Request<BodyType>,
+    ) -> Result<Response<Incoming>, TrySendError<Request<BodyType>>> {
+        match self {
+            Sender::Http1(sender) => sender.try_send_request(req).await,
+            Sender::Http2(sender) => sender.try_send_request(req).await,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            Sender::Http1(sender) => sender.is_closed(),
+            Sender::Http2(sender) => sender.is_closed(),
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        match self {
+            Sender::Http1(sender) => sender.is_ready(),
+            Sender::Http2(sender) => sender.is_ready(),
+        }
+    }
+}
+
+struct Idle {
+    sender: Sender,
+    since: Instant,
+}
+
+pub struct Pool {
+    settings: PoolSettings,
+    idle: Mutex<HashMap<PoolKey, Vec<Idle>>>,
+    reaper_started: AtomicBool,
+}
+
+impl Pool {
+    pub fn new(settings: PoolSettings) -> Self {
+        Pool {
+            settings,
+            idle: Mutex::new(HashMap::new()),
+            reaper_started: AtomicBool::new(false),
+        }
+    }
+
+    // Take the most recently used idle connection for the key
+    pub fn checkout(&self, key: &PoolKey) -> Option<Sender> {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let connections = idle.get_mut(key)?;
+        while let Some(connection) = connections.last_mut() {
+            if connection.since.elapsed() < self.settings.idle_timeout
+                && !connection.sender.is_closed()
+                && connection.sender.is_ready()
+            {
+                debug!("Reusing connection to {}:{}", key.host, key.port);
+                if let Sender::Http2(sender) = &connection.sender {
+                    connection.since = Instant::now();
+                    return Some(Sender::Http2(sender.clone()));
+                }
+                return connections.pop().map(|connection| connection.sender);
+            }
+            connections.pop();
+        }
+        idle.remove(key);
+        None
+    }
+
+    // Return the connection to the pool once the response to the last request is read
+    pub fn checkin(self: &Arc<Self>, key: PoolKey, sender: Sender) {
+        if self.settings.max_idle_per_host == 0 {
+            return;
+        }
+        self.start_reaper();
+        let mut sender = match sender {
+            Sender::Http1(sender) => sender,
+            Sender::Http2(sender) => return self.share(key, sender),
+        };
+        let pool = Arc::downgrade(self);
+        tokio::task::spawn(async move {
+            if sender.ready().await.is_err() {
+                return;
+            }
+            let Some(pool) = pool.upgrade() else {
+                return;
+            };
+            let mut idle = pool.idle.lock().unwrap_or_else(|e| e.into_inner());
+            let connections = idle.entry(key).or_default();
+            if connections.len() < pool.settings.max_idle_per_host {
+                connections.push(Idle {
+                    sender: Sender::Http1(sender),
+                    since: Instant::now(),
+                });
+            }
+        });
+    }
+
+    // Keep a single HTTP/2 connection per key, it serves concurrent requests by itself
+    fn share(&self, key: PoolKey, sender: http2::SendRequest<BodyType>) {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let connections = idle.entry(key).or_default();
+        let shared = connections.iter_mut().find(|connection| {
+            matches!(connection.sender, Sender::Http2(_)) && !connection.sender.is_closed()
+        });
+        if let Some(connection) = shared {
+            connection.since = Instant::now();
+        } else if connections.len() < self.settings.max_idle_per_host {
+            connections.push(Idle {
+                sender: Sender::Http2(sender),
+                since: Instant::now(),
+            });
+        }
+    }
+
+    fn start_reaper(self: &Arc<Self>) {
+        if self.reaper_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let pool = Arc::downgrade(self);
+        let period = (self.settings.idle_timeout / 2).max(Duration::from_secs(1));
+        tokio::task::spawn(reap_idle(pool, period));
+    }
+
+    fn remove_expired(&self) {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        idle.retain(|_, connections| {
+            connections.retain(|connection| {
+                connection.since.elapsed() < self.settings.idle_timeout
+                    && !connection.sender.is_closed()
+            });
+            !connections.is_empty()
+        });
+    }
+}
+
+// Closes expired connections until the pool is dropped
+async fn reap_idle(pool: Weak<Pool>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match pool.upgrade() {
+            Some(pool) => pool.remove_expired(),
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Empty};
+    use hyper::service::service_fn;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use std::convert::Infallible;
+    use tokio::io::duplex;
+
+    async fn ok(_req: Request<Incoming>) -> Result<Response<Empty<Bytes>>, Infallible> {
+        Ok(Response::new(Empty::new()))
+    }
+
+    async fn http1_sender() -> Sender {
+        let (client, server) = duplex(4096);
+        tokio::spawn(
+            hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(server), service_fn(ok)),
+        );
+        let (sender, conn) = http1::handshake(TokioIo::new(client)).await.unwrap();
+        tokio::spawn(conn);
+        Sender::Http1(sender)
+    }
+
+    async fn http2_sender() -> Sender {
+        let (client, server) = duplex(65536);
+        tokio::spawn(
+            hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(server), service_fn(ok)),
+        );
+        let (sender, conn) = http2::handshake(TokioExecutor::new(), TokioIo::new(client))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        Sender::Http2(sender)
+    }
+
+    fn tls_config() -> Arc<ClientConfig> {
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        Arc::new(
+            ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(rustls::RootCertStore::empty())
+                .with_no_client_auth(),
+        )
+    }
+
+    fn idle_count(pool: &Pool, key: &PoolKey) -> usize {
+        pool.idle.lock().unwrap().get(key).map_or(0, Vec::len)
+    }
+
+    // HTTP/1 connections are checked in once they are ready, which happens in a task
+    async fn wait_idle(pool: &Pool, key: &PoolKey, count: usize) {
+        for _ in 0..100 {
+            if idle_count(pool, key) == count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("pool has {} idle connections", idle_count(pool, key));
+    }
+
+    fn empty_request() -> Request<BodyType> {
+        Request::new(Empty::new().map_err(|never| match never {}).boxed())
+    }
+
+    #[tokio::test]
+    async fn reuses_http1_connections() {
+        let pool = Arc::new(Pool::new(PoolSettings::default()));
+        let key = PoolKey::new("Example.com", 80, None);
+        pool.checkin(key.clone(), http1_sender().await);
+        wait_idle(&pool, &key, 1).await;
+
+        let mut sender = pool
+            .checkout(&PoolKey::new("example.com", 80, None))
+            .unwrap();
+        // An HTTP/1 connection serves one request at a time, so it is taken out of the pool
+        assert!(pool.checkout(&key).is_none());
+        let response = sender.send_request(empty_request()).await.unwrap();
+        assert!(response.status().is_success());
+
+        pool.checkin(key.clone(), sender);
+        wait_idle(&pool, &key, 1).await;
+    }
+
+    #[tokio::test]
+    async fn shares_http2_connections() {
+        let pool = Arc::new(Pool::new(PoolSettings::default()));
+        let key = PoolKey::new("example.com", 443, None);
+        pool.checkin(key.clone(), http2_sender().await);
+        pool.checkin(key.clone(), http2_sender().await);
+        assert_eq!(idle_count(&pool, &key), 1);
+
+        let first = pool.checkout(&key).unwrap();
+        let second = pool.checkout(&key).unwrap();
+        assert_eq!(first.version(), Version::HTTP_2);
+        assert_eq!(second.version(), Version::HTTP_2);
+        assert_eq!(idle_count(&pool, &key), 1);
+    }
+
+    #[tokio::test]
+    async fn keys_connections_by_tls_config() {
+        let (first, second) = (tls_config(), tls_config());
+        let first_key = PoolKey::new("example.com", 443, Some(&first));
+        let second_key = PoolKey::new("example.com", 443, Some(&second));
+        assert_eq!(first_key, PoolKey::new("example.com", 443, Some(&first)));
+        assert_ne!(first_key, second_key);
+        assert_ne!(
+            PoolKey::new("example.com", 443, None),
+            PoolKey::forward("example.com", 443)
+        );
+
+        let pool = Arc::new(Pool::new(PoolSettings::default()));
+        pool.checkin(first_key.clone(), http1_sender().await);
+        wait_idle(&pool, &first_key, 1).await;
+        assert!(pool.checkout(&second_key).is_none());
+        assert!(pool.checkout(&first_key).is_some());
+    }
+
+    #[tokio::test]
+    async fn expires_idle_connections() {
+        let pool = Arc::new(Pool::new(PoolSettings {
+            idle_timeout: Duration::from_millis(50),
+            max_idle_per_host: 8,
+        }));
+        let key = PoolKey::new("example.com", 80, None);
+        pool.checkin(key.clone(), http1_sender().await);
+        wait_idle(&pool, &key, 1).await;
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        pool.remove_expired();
+        assert_eq!(idle_count(&pool, &key), 0);
+        assert!(pool.checkout(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn limits_idle_connections_per_host() {
+        let pool = Arc::new(Pool::new(PoolSettings {
+            idle_timeout: Duration::from_secs(90),
+            max_idle_per_host: 1,
+        }));
+        let key = PoolKey::new("example.com", 80, None);
+        pool.checkin(key.clone(), http1_sender().await);
+        pool.checkin(key.clone(), http1_sender().await);
+        wait_idle(&pool, &key, 1).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(idle_count(&pool, &key), 1);
+    }
+}
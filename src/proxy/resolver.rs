@@ -0,0 +1,234 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use super::host_pattern::{find_for_host, HostPattern};
+use hickory_resolver::config::{NameServerConfig, ResolverConfig};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::xfer::Protocol;
+use hickory_resolver::TokioResolver;
+use log::{debug, warn};
+
+const DNS_PORT: u16 = 53;
+pub const DEFAULT_DNS_CACHE_SIZE: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub struct ResolverSettings {
+    // Hosts resolved to fixed addresses, the first matching pattern is used
+    pub overrides: Vec<(HostPattern, Vec<IpAddr>)>,
+    // DNS servers to ask instead of the system ones
+    pub nameservers: Vec<SocketAddr>,
+    // Number of cached answers, which are kept for their TTL
+    pub cache_size: usize,
+}
+
+impl Default for ResolverSettings {
+    fn default() -> Self {
+        ResolverSettings {
+            overrides: Vec::new(),
+            nameservers: Vec::new(),
+            cache_size: DEFAULT_DNS_CACHE_SIZE,
+        }
+    }
+}
+
+impl ResolverSettings {
+    // Parse overrides written as "<host pattern>=<ip>[,<ip>...];..."
+    pub fn parse_overrides(s: &str) -> Result<Vec<(HostPattern, Vec<IpAddr>)>, String> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (pattern, ips) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected <host>=<ip>, got {:?}", entry))?;
+                let ips = ips
+                    .split(',')
+                    .map(|ip| {
+                        ip.trim()
+                            .parse()
+                            .map_err(|_| format!("invalid ip address {:?}", ip.trim()))
+                    })
+                    .collect::<Result<Vec<IpAddr>, String>>()?;
+                Ok((pattern.parse()?, ips))
+            })
+            .collect()
+    }
+
+    // Parse servers written as "<ip>[:port];...", addresses with a port are written as
+    // "1.1.1.1:53" or "[2606:4700::1111]:53"
+    pub fn parse_nameservers(s: &str) -> Result<Vec<SocketAddr>, String> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse()
+                    .or_else(|_| entry.parse().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+                    .map_err(|_| format!("invalid DNS server {:?}", entry))
+            })
+            .collect()
+    }
+}
+
+// Resolves upstream hosts, the host name itself is kept for SNI and the Host header
+pub struct Resolver {
+    overrides: Vec<(HostPattern, Vec<IpAddr>)>,
+    // None when the system configuration can not be read, getaddrinfo is used then
+    dns: Option<TokioResolver>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver::new(ResolverSettings::default())
+    }
+}
+
+impl Resolver {
+    pub fn new(settings: ResolverSettings) -> Self {
+        let provider = TokioConnectionProvider::default();
+        let builder = match settings.nameservers.is_empty() {
+            true => TokioResolver::builder(provider)
+                .inspect_err(|e| warn!("Using the system resolver without cache: {}", e))
+                .ok(),
+            false => {
+                let mut config = ResolverConfig::new();
+                for &server in &settings.nameservers {
+                    config.add_name_server(NameServerConfig::new(server, Protocol::Udp));
+                    config.add_name_server(NameServerConfig::new(server, Protocol::Tcp));
+                }
+                Some(TokioResolver::builder_with_config(config, provider))
+            }
+        };
+        let dns = builder.map(|mut builder| {
+            builder.options_mut().cache_size = settings.cache_size;
+            builder.build()
+        });
+        Resolver {
+            overrides: settings.overrides,
+            dns,
+        }
+    }
+
+    // Fixed addresses of the host, if it is overridden
+    pub fn override_for(&self, host: &str) -> Option<&[IpAddr]> {
+        find_for_host(&self.overrides, host).map(Vec::as_slice)
+    }
+
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        if let Some(ips) = self.override_for(host) {
+            debug!("Resolving {} to overridden {:?}", host, ips);
+            return Ok(ips.iter().map(|&ip| SocketAddr::new(ip, port)).collect());
+        }
+        match &self.dns {
+            Some(dns) => dns
+                .lookup_ip(host)
+                .await
+                .map(|ips| ips.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+                .map_err(io::Error::other),
+            None => tokio::net::lookup_host((host, port))
+                .await
+                .map(Iterator::collect),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    // Nothing listens on the discard port, so any real query fails
+    fn resolver(overrides: &str) -> Resolver {
+        Resolver::new(ResolverSettings {
+            overrides: ResolverSettings::parse_overrides(overrides).unwrap(),
+            nameservers: vec![addr("127.0.0.1:9")],
+            ..ResolverSettings::default()
+        })
+    }
+
+    #[test]
+    fn parses_overrides() {
+        let overrides =
+            ResolverSettings::parse_overrides(" api.local=10.0.0.1, ::1 ;; *.test=127.0.0.1")
+                .unwrap();
+        assert_eq!(overrides.len(), 2);
+        assert!(overrides[0].0.matches("api.local"));
+        assert_eq!(overrides[0].1, [ip("10.0.0.1"), ip("::1")]);
+        assert!(overrides[1].0.matches("a.test"));
+        assert_eq!(overrides[1].1, [ip("127.0.0.1")]);
+    }
+
+    #[test]
+    fn rejects_invalid_overrides() {
+        for s in [
+            "api.local",
+            "api.local=",
+            "api.local=10.0.0.256",
+            "=10.0.0.1",
+        ] {
+            assert!(
+                ResolverSettings::parse_overrides(s).is_err(),
+                "{:?} is accepted",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn parses_nameservers() {
+        assert_eq!(
+            ResolverSettings::parse_nameservers("1.1.1.1; 9.9.9.9:5353;[2606:4700::1111]:53;::1")
+                .unwrap(),
+            [
+                addr("1.1.1.1:53"),
+                addr("9.9.9.9:5353"),
+                addr("[2606:4700::1111]:53"),
+                addr("[::1]:53"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_nameservers() {
+        for s in ["dns.google", "1.1.1.1:port", "1.1.1", "[::1]"] {
+            assert!(
+                ResolverSettings::parse_nameservers(s).is_err(),
+                "{:?} is accepted",
+                s
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn override_wins_over_dns() {
+        let resolver = resolver("api.local=10.0.0.1,10.0.0.2");
+        assert_eq!(
+            resolver.lookup("API.local", 443).await.unwrap(),
+            [addr("10.0.0.1:443"), addr("10.0.0.2:443")]
+        );
+        assert_eq!(resolver.override_for("other.local"), None);
+    }
+
+    #[tokio::test]
+    async fn ip_literals_skip_lookup() {
+        let resolver = resolver("*=10.0.0.1");
+        assert_eq!(
+            resolver.lookup("192.0.2.7", 80).await.unwrap(),
+            [addr("192.0.2.7:80")]
+        );
+        assert_eq!(
+            resolver.lookup("2001:db8::1", 80).await.unwrap(),
+            [addr("[2001:db8::1]:80")]
+        );
+    }
+}
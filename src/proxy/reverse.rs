@@ -0,0 +1,380 @@
+use std::str::FromStr;
+
+use super::host_pattern::HostPattern;
+use super::utils::request_host;
+use http::uri::PathAndQuery;
+use http::{header, request, HeaderMap, HeaderValue};
+
+// Service the reverse proxy forwards to, written as "http[s]://host[:port][/path]"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upstream {
+    pub is_https: bool,
+    pub host: String,
+    pub port: u16,
+    // Replaces the route prefix in forwarded paths, empty or starting with '/'
+    pub path: String,
+}
+
+impl Upstream {
+    // Host with the port unless it is the default one for the scheme
+    pub fn authority(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        match (self.is_https, self.port) {
+            (true, 443) | (false, 80) => host,
+            _ => format!("{}:{}", host, self.port),
+        }
+    }
+
+    pub fn origin(&self) -> String {
+        format!("{}://{}", scheme(self.is_https), self.authority())
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (is_https, rest) = if let Some(rest) = s.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(format!(
+                "unsupported upstream {:?}, expected http:// or https://",
+                s
+            ));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .map_err(|_| format!("invalid upstream port {:?}", port))?,
+            ),
+            _ => (authority, if is_https { 443 } else { 80 }),
+        };
+        let host = host.trim_matches(|c| c == '[' || c == ']');
+        if host.is_empty() {
+            return Err(format!("no host in upstream {:?}", s));
+        }
+        Ok(Upstream {
+            is_https,
+            host: host.to_string(),
+            port,
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+// Requests for the host (any when not set) with paths under the prefix go to the upstream.
+// Written as "[host pattern]/prefix=upstream", e.g. "api.local/v1=https://127.0.0.1:8443"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReverseRoute {
+    pub host: Option<HostPattern>,
+    // Without the trailing '/', empty for the whole host
+    pub path_prefix: String,
+    pub upstream: Upstream,
+}
+
+impl ReverseRoute {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        if let Some(pattern) = &self.host {
+            if !pattern.matches(host) {
+                return false;
+            }
+        }
+        strip_path_prefix(path, &self.path_prefix).is_some()
+    }
+}
+
+impl FromStr for ReverseRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (matcher, upstream) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <host>/<prefix>=<upstream>, got {:?}", s))?;
+        let matcher = matcher.trim();
+        let (host, path_prefix) = match matcher.find('/') {
+            Some(idx) => matcher.split_at(idx),
+            None => (matcher, ""),
+        };
+        let host = match host {
+            "" => None,
+            host => Some(host.parse()?),
+        };
+        Ok(ReverseRoute {
+            host,
+            path_prefix: path_prefix.trim_end_matches('/').to_string(),
+            upstream: upstream.parse()?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReverseProxySettings {
+    // The first matching route is used
+    pub routes: Vec<ReverseRoute>,
+    // Send the Host header of the client instead of the upstream authority
+    pub preserve_host: bool,
+    // Point redirects to the upstream back to the proxy
+    pub rewrite_location: bool,
+}
+
+impl Default for ReverseProxySettings {
+    fn default() -> Self {
+        ReverseProxySettings {
+            routes: Vec::new(),
+            preserve_host: false,
+            rewrite_location: true,
+        }
+    }
+}
+
+impl ReverseProxySettings {
+    // Parse routes separated with ';'
+    pub fn parse_routes(s: &str) -> Result<Vec<ReverseRoute>, String> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    pub fn route_for(&self, parts: &request::Parts) -> Option<&ReverseRoute> {
+        let host = request_host(parts).unwrap_or_default();
+        self.routes
+            .iter()
+            .find(|route| route.matches(&host, parts.uri.path()))
+    }
+
+    // Make the request origin-form for the upstream, replacing the route prefix with its path
+    pub fn rewrite_request(&self, route: &ReverseRoute, parts: &mut request::Parts) {
+        let path = strip_path_prefix(parts.uri.path(), &route.path_prefix).unwrap_or("");
+        let mut path_and_query = format!("{}{}", route.upstream.path, path);
+        if path_and_query.is_empty() {
+            path_and_query.push('/');
+        }
+        if let Some(query) = parts.uri.query() {
+            path_and_query.push('?');
+            path_and_query.push_str(query);
+        }
+        if let Ok(path_and_query) = PathAndQuery::from_str(&path_and_query) {
+            parts.uri = path_and_query.into();
+        }
+        if !self.preserve_host {
+            if let Ok(value) = HeaderValue::from_str(&route.upstream.authority()) {
+                parts.headers.insert(header::HOST, value);
+            }
+        }
+    }
+
+    // Redirects to the upstream are turned into redirects to the proxy at `client_origin`
+    pub fn rewrite_response(
+        &self,
+        route: &ReverseRoute,
+        client_origin: &str,
+        headers: &mut HeaderMap,
+    ) {
+        if !self.rewrite_location {
+            return;
+        }
+        let Some(location) = headers
+            .get(header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return;
+        };
+        let rewritten = match location.strip_prefix(&route.upstream.origin()) {
+            Some(path) if path.is_empty() || path.starts_with('/') => {
+                format!("{}{}", client_origin, proxy_path(route, path))
+            }
+            Some(_) => return,
+            None if location.starts_with('/') && !location.starts_with("//") => {
+                proxy_path(route, location)
+            }
+            None => return,
+        };
+        if let Ok(value) = HeaderValue::from_str(&rewritten) {
+            headers.insert(header::LOCATION, value);
+        }
+    }
+}
+
+// Origin the client addressed the proxy with, used for rewritten redirects
+pub fn client_origin(parts: &request::Parts, is_tls: bool) -> Option<String> {
+    let host = parts.headers.get(header::HOST)?.to_str().ok()?;
+    Some(format!("{}://{}", scheme(is_tls), host))
+}
+
+// Upstream path back to the path under the route prefix
+fn proxy_path(route: &ReverseRoute, path: &str) -> String {
+    match strip_path_prefix(path, &route.upstream.path) {
+        Some(rest) => match format!("{}{}", route.path_prefix, rest) {
+            path if path.is_empty() => String::from("/"),
+            path => path,
+        },
+        None => path.to_string(),
+    }
+}
+
+// The rest of the path when it is the prefix itself or lies under it
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix)?;
+    match rest.is_empty() || rest.starts_with('/') || rest.starts_with('?') {
+        true => Some(rest),
+        false => None,
+    }
+}
+
+fn scheme(is_tls: bool) -> &'static str {
+    if is_tls {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::utils::test_request;
+    use http::Method;
+
+    fn upstream(s: &str) -> Upstream {
+        s.parse().unwrap()
+    }
+
+    fn request(host: &str, uri: &str) -> request::Parts {
+        let mut parts = test_request(Method::GET, uri);
+        parts.headers.insert(header::HOST, host.parse().unwrap());
+        parts
+    }
+
+    fn settings(routes: &str) -> ReverseProxySettings {
+        ReverseProxySettings {
+            routes: ReverseProxySettings::parse_routes(routes).unwrap(),
+            ..ReverseProxySettings::default()
+        }
+    }
+
+    #[test]
+    fn parses_upstreams() {
+        assert_eq!(
+            upstream("https://api.internal:8443/base/"),
+            Upstream {
+                is_https: true,
+                host: "api.internal".to_string(),
+                port: 8443,
+                path: "/base".to_string(),
+            }
+        );
+        let plain = upstream("http://127.0.0.1");
+        assert_eq!((plain.port, plain.path.as_str()), (80, ""));
+        assert!("ftp://host".parse::<Upstream>().is_err());
+        assert!("http://:8080".parse::<Upstream>().is_err());
+        assert!("http://host:port".parse::<Upstream>().is_err());
+    }
+
+    #[test]
+    fn brackets_ipv6_authorities() {
+        let ipv6 = upstream("http://[::1]:8080/app");
+        assert_eq!(ipv6.host, "::1");
+        assert_eq!(ipv6.authority(), "[::1]:8080");
+        assert_eq!(ipv6.origin(), "http://[::1]:8080");
+        assert_eq!(upstream("https://[fd00::2]").authority(), "[fd00::2]");
+        assert_eq!(
+            upstream("https://example.com:443").authority(),
+            "example.com"
+        );
+        assert_eq!(
+            upstream("http://example.com:443").authority(),
+            "example.com:443"
+        );
+    }
+
+    #[test]
+    fn parses_routes() {
+        let route: ReverseRoute = "api.local/v1/=https://127.0.0.1:8443".parse().unwrap();
+        assert_eq!(route.host, Some("api.local".parse().unwrap()));
+        assert_eq!(route.path_prefix, "/v1");
+        assert_eq!(route.upstream, upstream("https://127.0.0.1:8443"));
+
+        let route: ReverseRoute = "/static=http://cdn.local".parse().unwrap();
+        assert_eq!((route.host, route.path_prefix.as_str()), (None, "/static"));
+        assert!("api.local".parse::<ReverseRoute>().is_err());
+        assert!("api.local=cdn.local".parse::<ReverseRoute>().is_err());
+    }
+
+    #[test]
+    fn routes_by_host_and_path_prefix() {
+        let settings =
+            settings("api.local/v1=http://v1.internal;*.local=http://web.internal;/=http://other");
+        let route = |host, uri| {
+            settings
+                .route_for(&request(host, uri))
+                .map(|route| route.upstream.host.as_str())
+        };
+        assert_eq!(route("api.local:8080", "/v1/users"), Some("v1.internal"));
+        assert_eq!(route("api.local", "/v1"), Some("v1.internal"));
+        assert_eq!(route("api.local", "/v10"), Some("web.internal"));
+        assert_eq!(route("docs.local", "/"), Some("web.internal"));
+        assert_eq!(route("example.com", "/any"), Some("other"));
+        assert_eq!(
+            self::settings("api.local=http://a").route_for(&request("b.local", "/")),
+            None
+        );
+    }
+
+    #[test]
+    fn rewrites_paths_and_host() {
+        let settings = settings("/api=http://[::1]:8080/v2");
+        let route = &settings.routes[0];
+        let mut parts = request("proxy.local", "/api/users?page=2");
+        settings.rewrite_request(route, &mut parts);
+        assert_eq!(parts.uri, "/v2/users?page=2");
+        assert_eq!(parts.headers[header::HOST], "[::1]:8080");
+
+        let settings = ReverseProxySettings {
+            preserve_host: true,
+            ..self::settings("/api=http://backend")
+        };
+        let mut parts = request("proxy.local", "/api");
+        settings.rewrite_request(&settings.routes[0], &mut parts);
+        assert_eq!(parts.uri, "/");
+        assert_eq!(parts.headers[header::HOST], "proxy.local");
+    }
+
+    #[test]
+    fn rewrites_redirects_to_the_proxy() {
+        let settings = settings("/api=http://backend:8080/v2");
+        let route = &settings.routes[0];
+        let redirect = |location: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::LOCATION, location.parse().unwrap());
+            settings.rewrite_response(route, "https://proxy.local", &mut headers);
+            headers[header::LOCATION].to_str().unwrap().to_string()
+        };
+        assert_eq!(
+            redirect("http://backend:8080/v2/login?next=1"),
+            "https://proxy.local/api/login?next=1"
+        );
+        assert_eq!(redirect("/v2/login"), "/api/login");
+        // Paths outside the upstream path are kept as they are
+        assert_eq!(
+            redirect("http://backend:8080/v20"),
+            "https://proxy.local/v20"
+        );
+        assert_eq!(
+            redirect("https://elsewhere.test/"),
+            "https://elsewhere.test/"
+        );
+    }
+}
@@ -0,0 +1,446 @@
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use super::host_pattern::HostPattern;
+use super::utils::{request_host, set_content_length};
+use super::BodyType;
+use bytes::Bytes;
+use http::{header, request, HeaderMap, HeaderName, HeaderValue, Method, Uri};
+use http_body_util::{BodyExt, Full};
+use log::{error, info, warn};
+use regex::bytes::{NoExpand, Regex};
+
+// Part of the exchange a rule rewrites
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    // "METHOD uri", the uri as sent by the client
+    RequestLine,
+    // Every header as a "name: value" line, the name is in lower case
+    RequestHeader,
+    RequestBody,
+    ResponseHeader,
+    ResponseBody,
+}
+
+impl Target {
+    fn is_header(&self) -> bool {
+        matches!(self, Target::RequestHeader | Target::ResponseHeader)
+    }
+
+    fn is_request(&self) -> bool {
+        matches!(
+            self,
+            Target::RequestLine | Target::RequestHeader | Target::RequestBody
+        )
+    }
+}
+
+// Exchanges a rule applies to, unset fields match everything
+#[derive(Clone, Debug, Default)]
+pub struct Scope {
+    pub host: Option<HostPattern>,
+    pub path_prefix: Option<String>,
+    pub method: Option<Method>,
+    // Matched case insensitively against the content type of the rewritten message
+    pub content_type: Option<String>,
+}
+
+impl Scope {
+    fn matches(&self, request: &request::Parts, headers: &HeaderMap) -> bool {
+        self.matches_request(request)
+            && self.content_type.as_ref().is_none_or(|expected| {
+                headers
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|value| {
+                        value
+                            .to_ascii_lowercase()
+                            .contains(&expected.to_ascii_lowercase())
+                    })
+            })
+    }
+
+    // Everything but the content type, which is known only for the rewritten message
+    fn matches_request(&self, request: &request::Parts) -> bool {
+        self.host
+            .as_ref()
+            .is_none_or(|pattern| request_host(request).is_some_and(|host| pattern.matches(&host)))
+            && self
+                .path_prefix
+                .as_ref()
+                .is_none_or(|prefix| request.uri.path().starts_with(prefix))
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|method| *method == request.method)
+    }
+}
+
+// Replace every match of `pattern` in the target with `replacement`.
+// Header rules with an empty literal pattern add the replacement as a new header,
+// header lines replaced with nothing are removed
+#[derive(Clone, Debug)]
+pub struct RewriteRule {
+    pub scope: Scope,
+    pub target: Target,
+    pub pattern: String,
+    // Regex replacements may refer to groups as $1 or ${name}
+    pub is_regex: bool,
+    pub replacement: String,
+    regex: Regex,
+}
+
+impl RewriteRule {
+    pub fn new(
+        scope: Scope,
+        target: Target,
+        pattern: String,
+        is_regex: bool,
+        replacement: String,
+    ) -> Result<Self, String> {
+        // An empty pattern matches between every two bytes, only header rules give it a meaning
+        if pattern.is_empty() && (is_regex || !target.is_header()) {
+            return Err(format!(
+                "empty pattern is only allowed in literal header rules, not in {:?} rules",
+                target
+            ));
+        }
+        let regex = match is_regex {
+            true => Regex::new(&pattern),
+            false => Regex::new(&regex::escape(&pattern)),
+        }
+        .map_err(|e| format!("invalid pattern {:?}: {}", pattern, e))?;
+        Ok(RewriteRule {
+            scope,
+            target,
+            pattern,
+            is_regex,
+            replacement,
+            regex,
+        })
+    }
+
+    fn replace(&self, haystack: &[u8]) -> Vec<u8> {
+        let replacement = self.replacement.as_bytes();
+        match self.is_regex {
+            true => self.regex.replace_all(haystack, replacement).into_owned(),
+            false => self
+                .regex
+                .replace_all(haystack, NoExpand(replacement))
+                .into_owned(),
+        }
+    }
+
+    fn adds_header(&self) -> bool {
+        !self.is_regex && self.pattern.is_empty()
+    }
+}
+
+// Rules shared by the proxy and the control api, replaced as a whole
+#[derive(Default)]
+pub struct Rewriter {
+    rules: RwLock<Arc<Vec<RewriteRule>>>,
+}
+
+impl Rewriter {
+    pub fn new(rules: Vec<RewriteRule>) -> Self {
+        Rewriter {
+            rules: RwLock::new(Arc::new(rules)),
+        }
+    }
+
+    // Rules in effect, an exchange keeps using the rules it started with
+    pub fn rules(&self) -> Arc<Vec<RewriteRule>> {
+        self.rules.read().unwrap().clone()
+    }
+
+    pub fn set_rules(&self, rules: Vec<RewriteRule>) {
+        info!("Rewrite rules are replaced with {} rules", rules.len());
+        *self.rules.write().unwrap() = Arc::new(rules);
+    }
+}
+
+// Apply the request rules, the body is buffered only when a body rule applies
+pub async fn rewrite_request(
+    rules: &[RewriteRule],
+    parts: &mut request::Parts,
+    body: BodyType,
+) -> Result<BodyType, hyper::Error> {
+    let request_rules: Vec<&RewriteRule> = rules
+        .iter()
+        .filter(|rule| rule.target.is_request() && rule.scope.matches(parts, &parts.headers))
+        .collect();
+    for rule in request_rules
+        .iter()
+        .filter(|rule| rule.target == Target::RequestLine)
+    {
+        rewrite_request_line(rule, parts);
+    }
+    rewrite_headers(&request_rules, Target::RequestHeader, &mut parts.headers);
+    // Response body rules work on plain text, so the upstream is not offered compression
+    if rules
+        .iter()
+        .any(|rule| rule.target == Target::ResponseBody && rule.scope.matches_request(parts))
+    {
+        parts.headers.remove(header::ACCEPT_ENCODING);
+    }
+    rewrite_body(
+        &request_rules,
+        Target::RequestBody,
+        &mut parts.headers,
+        body,
+    )
+    .await
+}
+
+// Apply the response rules, scopes are matched against the request of the exchange
+pub async fn rewrite_response(
+    rules: &[RewriteRule],
+    request: &request::Parts,
+    headers: &mut HeaderMap,
+    body: BodyType,
+) -> Result<BodyType, hyper::Error> {
+    let rules: Vec<&RewriteRule> = rules
+        .iter()
+        .filter(|rule| !rule.target.is_request() && rule.scope.matches(request, headers))
+        .collect();
+    rewrite_headers(&rules, Target::ResponseHeader, headers);
+    rewrite_body(&rules, Target::ResponseBody, headers, body).await
+}
+
+fn rewrite_request_line(rule: &RewriteRule, parts: &mut request::Parts) {
+    let line = format!("{} {}", parts.method, parts.uri);
+    let rewritten = rule.replace(line.as_bytes());
+    if rewritten == line.as_bytes() {
+        return;
+    }
+    let rewritten = String::from_utf8_lossy(&rewritten);
+    let parsed = rewritten.split_once(' ').and_then(|(method, uri)| {
+        Some((
+            Method::from_str(method).ok()?,
+            Uri::from_str(uri.trim()).ok()?,
+        ))
+    });
+    match parsed {
+        Some((method, uri)) => {
+            parts.method = method;
+            parts.uri = uri;
+        }
+        None => error!(
+            "Rewrite rule {:?} produced invalid request line {:?}",
+            rule.pattern, rewritten
+        ),
+    }
+}
+
+fn rewrite_headers(rules: &[&RewriteRule], target: Target, headers: &mut HeaderMap) {
+    for rule in rules.iter().filter(|rule| rule.target == target) {
+        if rule.adds_header() {
+            match parse_header_line(rule.replacement.as_bytes()) {
+                Some((name, value)) => {
+                    headers.append(name, value);
+                }
+                None => error!("Rewrite rule adds invalid header {:?}", rule.replacement),
+            }
+            continue;
+        }
+        let mut rewritten = HeaderMap::with_capacity(headers.len());
+        for (name, value) in headers.iter() {
+            let mut line = name.as_str().as_bytes().to_vec();
+            line.extend_from_slice(b": ");
+            line.extend_from_slice(value.as_bytes());
+            let replaced = rule.replace(&line);
+            if replaced == line {
+                rewritten.append(name, value.clone());
+            } else if replaced.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            } else if let Some((name, value)) = parse_header_line(&replaced) {
+                rewritten.append(name, value);
+            } else {
+                error!(
+                    "Rewrite rule {:?} produced invalid header {:?}",
+                    rule.pattern,
+                    String::from_utf8_lossy(&replaced)
+                );
+                rewritten.append(name, value.clone());
+            }
+        }
+        *headers = rewritten;
+    }
+}
+
+async fn rewrite_body(
+    rules: &[&RewriteRule],
+    target: Target,
+    headers: &mut HeaderMap,
+    body: BodyType,
+) -> Result<BodyType, hyper::Error> {
+    let rules: Vec<&&RewriteRule> = rules.iter().filter(|rule| rule.target == target).collect();
+    if rules.is_empty() {
+        return Ok(body);
+    }
+    // Patterns would be matched against compressed bytes
+    if let Some(coding) = headers
+        .get(header::CONTENT_ENCODING)
+        .filter(|coding| !coding.as_bytes().eq_ignore_ascii_case(b"identity"))
+    {
+        warn!("Not rewriting body with Content-Encoding {:?}", coding);
+        return Ok(body);
+    }
+    let original = body.collect().await?.to_bytes();
+    let mut rewritten = original.to_vec();
+    for rule in rules {
+        rewritten = rule.replace(&rewritten);
+    }
+    let body = match rewritten == original {
+        true => original,
+        false => {
+            set_content_length(headers, rewritten.len());
+            Bytes::from(rewritten)
+        }
+    };
+    Ok(Full::new(body).map_err(|never| match never {}).boxed())
+}
+
+fn parse_header_line(line: &[u8]) -> Option<(HeaderName, HeaderValue)> {
+    let idx = line.iter().position(|&b| b == b':')?;
+    let name = HeaderName::from_bytes(line[..idx].trim_ascii()).ok()?;
+    let value = HeaderValue::from_bytes(line[idx + 1..].trim_ascii()).ok()?;
+    Some((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::utils::test_request;
+
+    fn rule(target: Target, pattern: &str, replacement: &str, scope: Scope) -> RewriteRule {
+        RewriteRule::new(
+            scope,
+            target,
+            pattern.to_string(),
+            false,
+            replacement.to_string(),
+        )
+        .unwrap()
+    }
+
+    fn request(uri: &str) -> request::Parts {
+        let mut parts = test_request(Method::GET, uri);
+        parts
+            .headers
+            .insert(header::ACCEPT_ENCODING, "gzip, br".parse().unwrap());
+        parts
+    }
+
+    fn body(data: &'static [u8]) -> BodyType {
+        Full::new(Bytes::from_static(data))
+            .map_err(|never| match never {})
+            .boxed()
+    }
+
+    async fn bytes(body: BodyType) -> Bytes {
+        body.collect().await.unwrap().to_bytes()
+    }
+
+    #[test]
+    fn rejects_empty_patterns_outside_headers() {
+        for target in [
+            Target::RequestLine,
+            Target::RequestBody,
+            Target::ResponseBody,
+        ] {
+            let rule = RewriteRule::new(Scope::default(), target, String::new(), false, "x".into());
+            assert!(rule.is_err(), "empty {:?} pattern is accepted", target);
+        }
+        let regex = RewriteRule::new(
+            Scope::default(),
+            Target::RequestHeader,
+            String::new(),
+            true,
+            "x-debug: 1".into(),
+        );
+        assert!(regex.is_err());
+        let adds_header = RewriteRule::new(
+            Scope::default(),
+            Target::ResponseHeader,
+            String::new(),
+            false,
+            "x-debug: 1".into(),
+        );
+        assert!(adds_header.unwrap().adds_header());
+    }
+
+    #[tokio::test]
+    async fn response_body_rule_disables_compression() {
+        let scope = Scope {
+            path_prefix: Some("/api".to_string()),
+            content_type: Some("json".to_string()),
+            ..Scope::default()
+        };
+        let rules = vec![rule(Target::ResponseBody, "a", "b", scope)];
+
+        let mut parts = request("http://example.com/api/users");
+        rewrite_request(&rules, &mut parts, body(b""))
+            .await
+            .unwrap();
+        assert!(!parts.headers.contains_key(header::ACCEPT_ENCODING));
+
+        let mut parts = request("http://example.com/static/app.js");
+        rewrite_request(&rules, &mut parts, body(b""))
+            .await
+            .unwrap();
+        assert!(parts.headers.contains_key(header::ACCEPT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn rewrites_response_body() {
+        let rules = vec![rule(
+            Target::ResponseBody,
+            "old",
+            "brand new",
+            Scope::default(),
+        )];
+        let parts = request("http://example.com/");
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("7"));
+        let rewritten = rewrite_response(&rules, &parts, &mut headers, body(b"old one"))
+            .await
+            .unwrap();
+        assert_eq!(bytes(rewritten).await, "brand new one");
+        assert_eq!(headers[header::CONTENT_LENGTH], "13");
+    }
+
+    #[tokio::test]
+    async fn leaves_encoded_bodies_alone() {
+        let rules = vec![rule(Target::ResponseBody, "old", "new", Scope::default())];
+        let parts = request("http://example.com/");
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let rewritten = rewrite_response(&rules, &parts, &mut headers, body(b"old"))
+            .await
+            .unwrap();
+        assert_eq!(bytes(rewritten).await, "old");
+    }
+
+    #[tokio::test]
+    async fn rewrites_request_line_and_headers() {
+        let rules = vec![
+            rule(Target::RequestLine, "/v1/", "/v2/", Scope::default()),
+            rule(Target::RequestHeader, "", "x-debug: 1", Scope::default()),
+            rule(
+                Target::RequestHeader,
+                "accept-encoding: gzip, br",
+                "",
+                Scope::default(),
+            ),
+        ];
+        let mut parts = request("http://example.com/v1/users");
+        rewrite_request(&rules, &mut parts, body(b""))
+            .await
+            .unwrap();
+        assert_eq!(parts.uri.path(), "/v2/users");
+        assert_eq!(parts.headers["x-debug"], "1");
+        assert!(!parts.headers.contains_key(header::ACCEPT_ENCODING));
+    }
+}
@@ -0,0 +1,265 @@
+use std::str::FromStr;
+
+use super::host_pattern::HostPattern;
+use super::utils::request_host;
+use http::{header, request, HeaderMap};
+
+// Exchanges described by a scope rule, written as space separated "key=value" terms:
+// scheme=<http|https> host=<host pattern> port=<port> path=<prefix> ext=<ext,...> type=<content type>,
+// e.g. "host=*.example.com path=/api" or "ext=png,jpg,woff2". Unset terms match everything
+#[derive(Clone, Debug, Default)]
+pub struct ScopeRule {
+    pub is_https: Option<bool>,
+    pub host: Option<HostPattern>,
+    pub port: Option<u16>,
+    pub path_prefix: Option<String>,
+    // Lower case, without the dot
+    pub extensions: Vec<String>,
+    // Matched case insensitively against the response content type, or the request one
+    // when the response has none
+    pub content_type: Option<String>,
+}
+
+impl ScopeRule {
+    // Everything but the content type, which is known only once the response arrives
+    fn matches_request(&self, exchange: &Exchange) -> bool {
+        self.is_https
+            .is_none_or(|is_https| is_https == exchange.is_https)
+            && self.host.as_ref().is_none_or(|pattern| {
+                request_host(exchange.request).is_some_and(|host| pattern.matches(&host))
+            })
+            && self.port.is_none_or(|port| port == exchange.port)
+            && self
+                .path_prefix
+                .as_ref()
+                .is_none_or(|prefix| exchange.request.uri.path().starts_with(prefix))
+            && (self.extensions.is_empty()
+                || extension(exchange.request.uri.path())
+                    .is_some_and(|ext| self.extensions.contains(&ext)))
+    }
+
+    fn matches(&self, exchange: &Exchange, response: &HeaderMap) -> bool {
+        self.matches_request(exchange)
+            && self.content_type.as_ref().is_none_or(|expected| {
+                content_type(response)
+                    .or_else(|| content_type(&exchange.request.headers))
+                    .is_some_and(|value| value.contains(expected.as_str()))
+            })
+    }
+}
+
+impl FromStr for ScopeRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rule = ScopeRule::default();
+        for term in s.split_whitespace() {
+            let (key, value) = term
+                .split_once('=')
+                .ok_or_else(|| format!("expected <key>=<value>, got {:?}", term))?;
+            match key {
+                "scheme" => {
+                    rule.is_https = Some(match value {
+                        "https" => true,
+                        "http" => false,
+                        _ => return Err(format!("unknown scheme {:?}", value)),
+                    })
+                }
+                "host" => rule.host = Some(value.parse()?),
+                "port" => {
+                    rule.port = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid port {:?}", value))?,
+                    )
+                }
+                "path" => rule.path_prefix = Some(value.to_string()),
+                "ext" => {
+                    rule.extensions = value
+                        .split(',')
+                        .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+                        .filter(|ext| !ext.is_empty())
+                        .collect()
+                }
+                "type" => rule.content_type = Some(value.to_ascii_lowercase()),
+                _ => {
+                    return Err(format!(
+                        "unknown key {:?}, expected scheme, host, port, path, ext or type",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(rule)
+    }
+}
+
+// Request of the exchange as seen by scope rules
+pub struct Exchange<'a> {
+    pub request: &'a request::Parts,
+    pub is_https: bool,
+    // Port of the upstream
+    pub port: u16,
+}
+
+// Decides which exchanges are passed to the callback. Excluded exchanges and, when includes
+// are set, exchanges matching none of them are still proxied but not recorded
+#[derive(Clone, Debug, Default)]
+pub struct CaptureScope {
+    pub include: Vec<ScopeRule>,
+    pub exclude: Vec<ScopeRule>,
+}
+
+impl CaptureScope {
+    // Parse rules separated with ';'
+    pub fn parse_rules(s: &str) -> Result<Vec<ScopeRule>, String> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    // False when the exchange is out of scope whatever the response is,
+    // so its bodies do not have to be captured at all
+    pub fn may_capture(&self, exchange: &Exchange) -> bool {
+        !self
+            .exclude
+            .iter()
+            .any(|rule| rule.content_type.is_none() && rule.matches_request(exchange))
+            && (self.include.is_empty()
+                || self
+                    .include
+                    .iter()
+                    .any(|rule| rule.matches_request(exchange)))
+    }
+
+    pub fn should_capture(&self, exchange: &Exchange, response: &HeaderMap) -> bool {
+        !self
+            .exclude
+            .iter()
+            .any(|rule| rule.matches(exchange, response))
+            && (self.include.is_empty()
+                || self
+                    .include
+                    .iter()
+                    .any(|rule| rule.matches(exchange, response)))
+    }
+}
+
+fn extension(path: &str) -> Option<String> {
+    let segment = path.rsplit('/').next()?;
+    let (_, ext) = segment.rsplit_once('.')?;
+    Some(ext.to_ascii_lowercase())
+}
+
+fn content_type(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    Some(value.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::utils::test_request;
+    use http::Method;
+
+    fn request(uri: &str) -> request::Parts {
+        test_request(Method::GET, uri)
+    }
+
+    fn response(content_type: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+        headers
+    }
+
+    fn scope(include: &str, exclude: &str) -> CaptureScope {
+        CaptureScope {
+            include: CaptureScope::parse_rules(include).unwrap(),
+            exclude: CaptureScope::parse_rules(exclude).unwrap(),
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule: ScopeRule =
+            "scheme=https host=*.example.com port=8443 path=/api ext=.PNG,jpg type=JSON"
+                .parse()
+                .unwrap();
+        assert_eq!(rule.is_https, Some(true));
+        assert_eq!(rule.host, Some("*.example.com".parse().unwrap()));
+        assert_eq!(rule.port, Some(8443));
+        assert_eq!(rule.path_prefix.as_deref(), Some("/api"));
+        assert_eq!(rule.extensions, vec!["png", "jpg"]);
+        assert_eq!(rule.content_type.as_deref(), Some("json"));
+
+        for invalid in [
+            "scheme=ftp",
+            "port=http",
+            "host",
+            "method=GET",
+            "host=www.*.com",
+        ] {
+            assert!(invalid.parse::<ScopeRule>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn matches_requests() {
+        let rule: ScopeRule = "scheme=https host=*.example.com port=443 path=/api ext=json"
+            .parse()
+            .unwrap();
+        let parts = request("https://www.example.com/api/data.JSON?x=1");
+        let exchange = |is_https, port| Exchange {
+            request: &parts,
+            is_https,
+            port,
+        };
+        assert!(rule.matches_request(&exchange(true, 443)));
+        assert!(!rule.matches_request(&exchange(false, 443)));
+        assert!(!rule.matches_request(&exchange(true, 8443)));
+        let other = request("https://www.example.com/static/data.json");
+        assert!(!rule.matches_request(&Exchange {
+            request: &other,
+            is_https: true,
+            port: 443,
+        }));
+    }
+
+    #[test]
+    fn applies_includes_and_excludes() {
+        let scope = scope("host=*.example.com", "ext=png;type=image/");
+        let check = |uri: &str, content_type: &str| {
+            let parts = request(uri);
+            let exchange = Exchange {
+                request: &parts,
+                is_https: false,
+                port: 80,
+            };
+            (
+                scope.may_capture(&exchange),
+                scope.should_capture(&exchange, &response(content_type)),
+            )
+        };
+        assert_eq!(check("http://api.example.com/", "text/html"), (true, true));
+        assert_eq!(check("http://other.test/", "text/html"), (false, false));
+        assert_eq!(
+            check("http://api.example.com/a.png", "text/html"),
+            (false, false)
+        );
+        // Content types are known only with the response
+        assert_eq!(
+            check("http://api.example.com/a", "image/webp"),
+            (true, false)
+        );
+        assert!(CaptureScope::default().should_capture(
+            &Exchange {
+                request: &request("http://any.test/"),
+                is_https: false,
+                port: 80,
+            },
+            &HeaderMap::new()
+        ));
+    }
+}
@@ -1,19 +1,30 @@
 use std::{future::Future, pin::Pin, sync::Arc};
 
-use super::utils::{clean_request, extract_host, parse_host_header};
-use super::{client::Client, utils::validate_request};
+use super::capture::{self, CaptureInfo};
+use super::certs::CertificateCache;
+use super::client::{Client, UpstreamError};
+use super::intercept::{InterceptQueue, Stage, Verdict};
+use super::onboarding;
+use super::reverse::{self, ReverseProxySettings};
+use super::rewrite::{self, Rewriter};
+use super::scope::{CaptureScope, Exchange};
+use super::shutdown::Shutdown;
+use super::timeouts::ClientTimeout;
+use super::utils::validate_request;
+use super::utils::{clean_request, extract_host, parse_host_header, request_host};
+use super::websocket::{self, FrameCallbackType};
 use bytes::Bytes;
 use http::{Request, Response};
 use http_body_util::{combinators::BoxBody, BodyExt, Full};
 use hyper::body::Incoming;
 use hyper::service::Service;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use std::sync::Mutex;
 
 pub type BodyType = BoxBody<Bytes, hyper::Error>;
 pub type CallbackType = Arc<
     Mutex<
-        dyn Fn((http::request::Parts, Bytes, bool), (http::response::Parts, Bytes)) -> ()
+        dyn Fn((http::request::Parts, Bytes, bool), (http::response::Parts, Bytes), CaptureInfo)
             + Send
             + 'static,
     >,
@@ -23,6 +34,27 @@ pub type CallbackType = Arc<
 pub struct ProxyService {
     pub is_tls: bool,
     pub callback: Option<CallbackType>,
+    pub frame_callback: Option<FrameCallbackType>,
+    pub certs: Arc<CertificateCache>,
+    pub client: Client,
+    // Maximum number of bytes of each body passed to the callback
+    pub capture_limit: usize,
+    // Exchanges out of the scope are not passed to the callbacks
+    pub scope: Arc<CaptureScope>,
+    // Destination of a stream accepted with a known target (e.g. through SOCKS),
+    // its requests are in origin form and are not validated as proxy requests
+    pub target: Option<(String, u16)>,
+    // Set for the reverse proxy listener, requests are routed to the configured upstreams
+    pub reverse: Option<Arc<ReverseProxySettings>>,
+    // Matching requests and responses are held here until released
+    pub intercept: Option<Arc<InterceptQueue>>,
+    pub rewriter: Option<Arc<Rewriter>>,
+    // Authenticated user the requests come from
+    pub user: Option<String>,
+    // Spawned tasks are waited for when the proxy stops
+    pub shutdown: Shutdown,
+    // Set when the client connection the requests come from is closed for a timeout
+    pub client_timeout: ClientTimeout,
 }
 
 impl Service<Request<Incoming>> for ProxyService {
@@ -40,47 +72,139 @@ impl Service<Request<Incoming>> for ProxyService {
             req.uri()
         );
 
-        Box::pin(process_proxy_request(
-            req,
-            self.is_tls,
-            self.callback.clone(),
-        ))
+        Box::pin(process_proxy_request(req, self.clone()))
     }
 }
 
 async fn process_proxy_request(
-    req: Request<Incoming>,
-    is_tls: bool,
-    callback: Option<CallbackType>,
+    mut req: Request<Incoming>,
+    service: ProxyService,
 ) -> Result<Response<BodyType>, hyper::Error> {
-    // Downloading request body in order to use callback later
-    // TODO: do not download the body if callback is not set (requires some extra magic with
-    // generics)
-    let (req_parts, req_body) = req.into_parts();
-    let req_body_bytes = req_body.collect().await?.to_bytes();
-    // we do not copy the request body because we are using Bytes, which is Arc under hood
-    let collected_body =
-        BodyType::new(Full::new(req_body_bytes.clone()).map_err(|never| match never {}));
+    let ProxyService {
+        is_tls,
+        callback,
+        frame_callback,
+        certs,
+        client,
+        capture_limit,
+        scope,
+        target,
+        reverse,
+        intercept,
+        rewriter,
+        user,
+        shutdown,
+        client_timeout,
+    } = service;
+    let exchange_id = capture::new_exchange_id();
+    let client_upgrade = websocket::is_upgrade_request(&req).then(|| hyper::upgrade::on(&mut req));
+    let (mut req_parts, req_body) = req.into_parts();
+    // HTTP/2 clients send the authority pseudo-header instead of Host, keep the exchange replayable
+    if !req_parts.headers.contains_key(http::header::HOST) {
+        if let Some(authority) = req_parts.uri.authority() {
+            if let Ok(value) = http::HeaderValue::from_str(authority.as_str()) {
+                req_parts.headers.insert(http::header::HOST, value);
+            }
+        }
+    }
+    // The magic host is answered before the request is routed, rewritten, held or captured
+    if request_host(&req_parts).is_some_and(|host| onboarding::is_magic_host(&host)) {
+        return Ok(onboarding::respond(req_parts.uri.path(), certs.ca()));
+    }
+    // The exchange is captured as sent to the upstream
+    let mut reverse_route = None;
+    if let Some(reverse) = &reverse {
+        let Some(route) = reverse.route_for(&req_parts).cloned() else {
+            return Ok(text_response(
+                http::StatusCode::NOT_FOUND,
+                "no reverse proxy route for the request",
+            ));
+        };
+        let origin = reverse::client_origin(&req_parts, is_tls);
+        reverse.rewrite_request(&route, &mut req_parts);
+        reverse_route = Some((route, origin));
+    }
+    let is_tls = reverse_route
+        .as_ref()
+        .map_or(is_tls, |(route, _)| route.upstream.is_https);
+
+    let mut req_body = BodyType::new(req_body);
+    let rewrite_rules = rewriter.as_ref().map(|rewriter| rewriter.rules());
+    if let Some(rules) = &rewrite_rules {
+        req_body = rewrite::rewrite_request(rules, &mut req_parts, req_body).await?;
+    }
+    // Held requests are captured with the changes made to them. Bodies are held in memory,
+    // so messages with bodies over the capture limit are forwarded without holding
+    if let Some(queue) = intercept
+        .as_ref()
+        .filter(|queue| queue.matches(Stage::Request, &req_parts))
+    {
+        req_body = match capture::collect_limited(req_body, capture_limit).await? {
+            Ok(mut body) => {
+                let verdict = queue
+                    .hold(
+                        Stage::Request,
+                        &exchange_id,
+                        is_tls,
+                        &req_parts,
+                        None,
+                        body.clone(),
+                    )
+                    .await;
+                match verdict {
+                    Verdict::Forward(edit) => {
+                        if let Some(edit) = edit {
+                            edit.apply_to_request(&mut req_parts, &mut body);
+                        }
+                    }
+                    Verdict::Drop => return Ok(dropped_response()),
+                }
+                full_body(body)
+            }
+            Err(body) => {
+                warn!(
+                    "Not holding request to {} with a body over {} bytes",
+                    req_parts.uri, capture_limit
+                );
+                body
+            }
+        };
+    }
 
     // The request is changed when proxy connection header is removed
-    let mut req = Request::from_parts(req_parts.clone(), collected_body);
+    let mut req = Request::from_parts(req_parts.clone(), req_body);
     let mut response: Response<BodyType>;
     let host: String;
     let port: u16;
 
-    if is_tls {
-        let full_host = extract_host(&req).unwrap();
-        (host, port) = parse_host_header(&full_host, 443).unwrap();
+    if let Some((route, _)) = &reverse_route {
+        host = route.upstream.host.clone();
+        port = route.upstream.port;
+    } else if let Some((target_host, target_port)) = target {
+        // Host names from the request are preferred to the target, which may be an address
+        host = extract_host(&req)
+            .and_then(|full_host| parse_host_header(&full_host, target_port).ok())
+            .map_or(target_host, |(host, _)| host);
+        port = target_port;
+    } else if is_tls {
+        let parsed = extract_host(&req)
+            .ok_or_else(|| String::from("no host in the request"))
+            .and_then(|full_host| parse_host_header(&full_host, 443));
+        (host, port) = match parsed {
+            Ok(parsed) => parsed,
+            Err(cause) => {
+                return Ok(text_response(
+                    http::StatusCode::BAD_REQUEST,
+                    &format!("invalid host: {}", cause),
+                ))
+            }
+        };
     } else {
         if let Err(cause) = validate_request(&req) {
-            return Ok(Response::builder()
-                .status(http::StatusCode::BAD_REQUEST)
-                .body(
-                    Full::new(Bytes::from(format!("{}", cause)))
-                        .map_err(|never| match never {})
-                        .boxed(),
-                )
-                .unwrap());
+            return Ok(text_response(
+                http::StatusCode::BAD_REQUEST,
+                &format!("{}", cause),
+            ));
         }
         // Safe unwrap since validate_request covers no host situation
         host = String::from(req.uri().host().unwrap());
@@ -88,28 +212,164 @@ async fn process_proxy_request(
         req = clean_request(req);
     }
 
+    // Bodies are streamed, the callback gets a copy of them limited by capture_limit
+    let exchange = Exchange {
+        request: &req_parts,
+        is_https: is_tls,
+        port,
+    };
+    let may_capture = scope.may_capture(&exchange);
+    let callback = callback.filter(|_| may_capture);
+    // Frames are captured as sent, so compression extensions are not negotiated for them
+    if client_upgrade.is_some() && frame_callback.is_some() && may_capture {
+        req.headers_mut()
+            .remove(http::header::SEC_WEBSOCKET_EXTENSIONS);
+        req_parts
+            .headers
+            .remove(http::header::SEC_WEBSOCKET_EXTENSIONS);
+    }
+    let req_capture = match callback {
+        Some(_) => {
+            let (parts, body) = req.into_parts();
+            let (body, captured) = capture::tee(body, capture_limit);
+            req = Request::from_parts(parts, body);
+            Some(captured)
+        }
+        None => None,
+    };
+
     debug!("Forwarding to {}:{}", host, port);
-    response = Client::send_request(req, host, port, is_tls).await?;
+    // Failed exchanges get a diagnostic response and are captured like any other
+    let mut upstream_error = None;
+    response = match client.send_request(req, host, port, is_tls).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Upstream request failed: {}", e);
+            let response = upstream_error_response(&e);
+            upstream_error = Some(e.to_string());
+            response
+        }
+    };
     debug!("Got response: {:?}", response);
+    if let (Some(reverse), Some((route, Some(origin)))) = (&reverse, &reverse_route) {
+        reverse.rewrite_response(route, origin, response.headers_mut());
+    }
+    if let Some(rules) = &rewrite_rules {
+        let (mut parts, body) = response.into_parts();
+        let body = rewrite::rewrite_response(rules, &req_parts, &mut parts.headers, body).await?;
+        response = Response::from_parts(parts, body);
+    }
+    if let Some(queue) = intercept.as_ref().filter(|queue| {
+        response.status() != http::StatusCode::SWITCHING_PROTOCOLS
+            && queue.matches(Stage::Response, &req_parts)
+    }) {
+        let (mut parts, body) = response.into_parts();
+        let body = match capture::collect_limited(body, capture_limit).await? {
+            Ok(mut body) => {
+                let verdict = queue
+                    .hold(
+                        Stage::Response,
+                        &exchange_id,
+                        is_tls,
+                        &req_parts,
+                        Some(&parts),
+                        body.clone(),
+                    )
+                    .await;
+                match verdict {
+                    Verdict::Forward(edit) => {
+                        if let Some(edit) = edit {
+                            edit.apply_to_response(&mut parts, &mut body);
+                        }
+                    }
+                    Verdict::Drop => return Ok(dropped_response()),
+                }
+                full_body(body)
+            }
+            Err(body) => {
+                warn!(
+                    "Not holding response from {} with a body over {} bytes",
+                    req_parts.uri, capture_limit
+                );
+                body
+            }
+        };
+        response = Response::from_parts(parts, body);
+    }
 
-    if let Some(callback) = callback {
-        let (response_parts, response_body) = response.into_parts();
-        let resp_body_bytes = response_body.collect().await?.to_bytes();
-
-        let callback = callback.lock();
-        match callback {
-            Ok(callback) => callback(
-                (req_parts, req_body_bytes, is_tls),
-                (response_parts.clone(), resp_body_bytes.clone()),
-            ),
-            Err(_) => error!("failed to use callback: the mutex is poisoned"),
+    let in_scope = scope.should_capture(
+        &Exchange {
+            request: &req_parts,
+            is_https: is_tls,
+            port,
+        },
+        response.headers(),
+    );
+    if let Some(client_upgrade) = client_upgrade {
+        if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+            let upstream_upgrade = hyper::upgrade::on(&mut response);
+            shutdown.spawn(websocket::relay(
+                client_upgrade,
+                upstream_upgrade,
+                exchange_id.clone(),
+                frame_callback.filter(|_| in_scope),
+                capture_limit,
+            ));
         }
-        response = Response::from_parts(
-            response_parts,
-            Full::new(resp_body_bytes)
-                .map_err(|never| match never {})
-                .boxed(),
-        );
+    }
+
+    if let (Some(callback), Some(req_capture), true) = (callback, req_capture, in_scope) {
+        let (response_parts, response_body) = response.into_parts();
+        let (response_body, resp_capture) = capture::tee(response_body, capture_limit);
+        let captured_parts = response_parts.clone();
+
+        // The exchange is reported once both bodies are done
+        shutdown.spawn(async move {
+            let req_body = req_capture.await.unwrap_or_default();
+            let resp_body = resp_capture.await.unwrap_or_default();
+            let incomplete = match (req_body.complete, resp_body.complete) {
+                (false, _) => Some("request body was not transferred to the end"),
+                (_, false) => Some("response body was not transferred to the end"),
+                _ => None,
+            };
+            let info = CaptureInfo {
+                id: exchange_id,
+                truncated: req_body.truncated || resp_body.truncated,
+                user,
+                error: upstream_error
+                    .or_else(|| client_timeout.reason())
+                    .or_else(|| incomplete.map(String::from)),
+            };
+            match callback.lock() {
+                Ok(callback) => callback(
+                    (req_parts, req_body.bytes, is_tls),
+                    (captured_parts, resp_body.bytes),
+                    info,
+                ),
+                Err(_) => error!("failed to use callback: the mutex is poisoned"),
+            }
+        });
+        response = Response::from_parts(response_parts, response_body);
     }
     Ok(response)
 }
+
+fn text_response(status: http::StatusCode, text: &str) -> Response<BodyType> {
+    // Safe unwrap since the status and body are always valid
+    Response::builder()
+        .status(status)
+        .body(full_body(Bytes::from(text.to_string())))
+        .unwrap()
+}
+
+fn upstream_error_response(e: &UpstreamError) -> Response<BodyType> {
+    text_response(e.status(), &format!("rusty-proxy: {}", e))
+}
+
+fn dropped_response() -> Response<BodyType> {
+    text_response(http::StatusCode::BAD_GATEWAY, "dropped by the proxy")
+}
+
+fn full_body(body: Bytes) -> BodyType {
+    Full::new(body).map_err(|never| match never {}).boxed()
+}
@@ -0,0 +1,194 @@
+use std::error::Error as StdError;
+use std::future::Future;
+use std::io;
+use std::pin::{pin, Pin};
+use std::time::Duration;
+
+use hyper::body::{Body, Incoming};
+use hyper::rt::bounds::Http2ServerConnExec;
+use hyper::rt::{Read, Write};
+use hyper::server::conn::{http1, http2};
+use hyper::service::HttpService;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+// Connections and captures get this long to finish unless configured otherwise
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Handle stopping the proxy. Once triggered the listeners stop accepting, connections
+// finish the requests in flight and tasks spawned through the handle are waited for
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    // Spawn a task the proxy waits for before exiting
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tasks.spawn(task)
+    }
+
+    // Wait for the spawned tasks, false if some are still running after the timeout
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.tasks.close();
+        tokio::time::timeout(timeout, self.tasks.wait())
+            .await
+            .is_ok()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.tasks.len()
+    }
+
+    // Drive the connection, asking it to close after the current request once triggered
+    pub(crate) async fn watch<C: GracefulConnection>(&self, conn: C) -> C::Output {
+        let mut conn = pin!(conn);
+        tokio::select! {
+            output = conn.as_mut() => output,
+            _ = self.triggered() => {
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+        }
+    }
+}
+
+// Resolves once SIGTERM or SIGINT is received
+#[cfg(unix)]
+pub async fn signal() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = interrupt.recv() => {},
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn signal() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+pub(crate) trait GracefulConnection: Future {
+    fn graceful_shutdown(self: Pin<&mut Self>);
+}
+
+impl<I, B, S> GracefulConnection for http1::UpgradeableConnection<I, S>
+where
+    S: HttpService<Incoming, ResBody = B>,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+    I: Read + Write + Unpin + Send + 'static,
+    B: Body + 'static,
+    B::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    fn graceful_shutdown(self: Pin<&mut Self>) {
+        http1::UpgradeableConnection::graceful_shutdown(self)
+    }
+}
+
+impl<I, B, S, E> GracefulConnection for http2::Connection<I, S, E>
+where
+    S: HttpService<Incoming, ResBody = B>,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+    I: Read + Write + Unpin,
+    B: Body + 'static,
+    B::Error: Into<Box<dyn StdError + Send + Sync>>,
+    E: Http2ServerConnExec<S::Future, B>,
+{
+    fn graceful_shutdown(self: Pin<&mut Self>) {
+        http2::Connection::graceful_shutdown(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http::{Request, Response};
+    use http_body_util::Empty;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn drain_waits_for_spawned_writes() {
+        let shutdown = Shutdown::new();
+        let written = Arc::new(AtomicUsize::new(0));
+        for delay in [10, 30, 50] {
+            let written = written.clone();
+            shutdown.spawn(async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                written.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        shutdown.trigger();
+        assert!(shutdown.drain(Duration::from_secs(5)).await);
+        assert_eq!(written.load(Ordering::SeqCst), 3);
+        assert_eq!(shutdown.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_after_timeout() {
+        let shutdown = Shutdown::new();
+        shutdown.spawn(tokio::time::sleep(Duration::from_secs(60)));
+        assert!(!shutdown.drain(Duration::from_millis(50)).await);
+        assert_eq!(shutdown.pending(), 1);
+    }
+
+    #[tokio::test]
+    async fn watch_closes_idle_connections_once_triggered() {
+        let shutdown = Shutdown::new();
+        let (client, server) = tokio::io::duplex(4096);
+        let service =
+            service_fn(|_req| async { Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new())) });
+        let conn = http1::Builder::new()
+            .serve_connection(TokioIo::new(server), service)
+            .with_upgrades();
+        let watched = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.watch(conn).await }
+        });
+
+        let (mut sender, client_conn) = hyper::client::conn::http1::handshake(TokioIo::new(client))
+            .await
+            .unwrap();
+        tokio::spawn(client_conn);
+        let response = sender
+            .send_request(Request::new(Empty::<Bytes>::new()))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert!(!watched.is_finished());
+
+        shutdown.trigger();
+        let closed = tokio::time::timeout(Duration::from_secs(5), watched).await;
+        assert!(closed.unwrap().unwrap().is_ok());
+    }
+}
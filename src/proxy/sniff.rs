@@ -0,0 +1,164 @@
+// Detection of the protocol spoken on a stream from its first bytes
+
+// First byte of a TLS record carrying a handshake message
+const TLS_HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST: u8 = 0x00;
+
+pub fn is_tls_handshake(head: &[u8]) -> bool {
+    head.first() == Some(&TLS_HANDSHAKE)
+}
+
+// A request line starts with an upper case method followed by a space
+pub fn is_http_request(head: &[u8]) -> bool {
+    let method_len = head.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    method_len >= 3 && head.get(method_len) == Some(&b' ')
+}
+
+// Server name from the ClientHello (RFC 8446, section 4.1.2 and RFC 6066, section 3).
+// None when there is none or the message is not received completely yet
+pub fn server_name(head: &[u8]) -> Option<String> {
+    let mut record = Reader(head);
+    if record.u8()? != TLS_HANDSHAKE {
+        return None;
+    }
+    record.skip(2)?;
+    let mut handshake = record.vec(2)?;
+    if handshake.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    let mut hello = handshake.vec(3)?;
+    // Version and random
+    hello.skip(2 + 32)?;
+    hello.vec(1)?;
+    hello.vec(2)?;
+    hello.vec(1)?;
+    let mut extensions = hello.vec(2)?;
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let mut extension = extensions.vec(2)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = extension.vec(2)?;
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec(2)?;
+            if name_type == NAME_TYPE_HOST {
+                return String::from_utf8(name.0.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    // A vector prefixed with its length of `len_size` bytes
+    fn vec(&mut self, len_size: usize) -> Option<Reader<'a>> {
+        let len = self
+            .take(len_size)?
+            .iter()
+            .fold(0usize, |len, &byte| len << 8 | byte as usize);
+        self.take(len).map(Reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Prefix the bytes with their length of `len_size` bytes
+    fn vec(len_size: usize, bytes: &[u8]) -> Vec<u8> {
+        let mut out = bytes.len().to_be_bytes()[8 - len_size..].to_vec();
+        out.extend_from_slice(bytes);
+        out
+    }
+
+    fn client_hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0xab; 32]);
+        hello.extend(vec(1, &[0x11; 32]));
+        hello.extend(vec(2, &[0x13, 0x01, 0x13, 0x02]));
+        hello.extend(vec(1, &[0x00]));
+        let mut encoded = Vec::new();
+        for (extension_type, data) in extensions {
+            encoded.extend_from_slice(&extension_type.to_be_bytes());
+            encoded.extend(vec(2, data));
+        }
+        hello.extend(vec(2, &encoded));
+        let mut handshake = vec![CLIENT_HELLO];
+        handshake.extend(vec(3, &hello));
+        let mut record = vec![TLS_HANDSHAKE, 0x03, 0x01];
+        record.extend(vec(2, &handshake));
+        record
+    }
+
+    fn server_name_extension(host: &str) -> (u16, Vec<u8>) {
+        let mut name = vec![NAME_TYPE_HOST];
+        name.extend(vec(2, host.as_bytes()));
+        (EXTENSION_SERVER_NAME, vec(2, &name))
+    }
+
+    #[test]
+    fn reads_server_name() {
+        // Supported versions comes before the server name
+        let hello = client_hello(&[
+            (0x002b, vec![2, 3, 4]),
+            server_name_extension("example.com"),
+        ]);
+        assert!(is_tls_handshake(&hello));
+        assert_eq!(server_name(&hello).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn no_server_name_without_extension() {
+        let hello = client_hello(&[(0x002b, vec![2, 3, 4])]);
+        assert!(is_tls_handshake(&hello));
+        assert_eq!(server_name(&hello), None);
+    }
+
+    #[test]
+    fn no_server_name_in_partial_hello() {
+        let hello = client_hello(&[server_name_extension("example.com")]);
+        for len in 0..hello.len() {
+            assert_eq!(server_name(&hello[..len]), None);
+        }
+    }
+
+    #[test]
+    fn detects_protocols() {
+        assert!(is_http_request(b"GET / HTTP/1.1\r\n"));
+        assert!(is_http_request(b"OPTIONS * HTTP/1.1\r\n"));
+        assert!(!is_http_request(b"GE"));
+        assert!(!is_http_request(b"get / HTTP/1.1\r\n"));
+        assert!(!is_http_request(b"SSH-2.0-OpenSSH_9.6\r\n"));
+        assert!(!is_tls_handshake(b"GET / HTTP/1.1\r\n"));
+        assert!(!is_tls_handshake(b""));
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), None);
+    }
+}
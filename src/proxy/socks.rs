@@ -0,0 +1,305 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use super::auth::ProxyCredentials;
+use super::client::UpstreamError;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+
+const COMMAND_CONNECT: u8 = 0x01;
+
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_TTL_EXPIRED: u8 = 0x06;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+// Credentials required from SOCKS clients, both parts are sent with one byte long length prefixes
+pub fn parse_credentials(s: &str) -> Result<ProxyCredentials, String> {
+    let credentials: ProxyCredentials = s.parse()?;
+    if credentials.user.len() > 255 || credentials.password.len() > 255 {
+        return Err(String::from(
+            "user and password must be at most 255 bytes long",
+        ));
+    }
+    Ok(credentials)
+}
+
+// Run the server side of the SOCKS5 handshake (RFC 1928, RFC 1929) and return the requested
+// destination. Only CONNECT is supported; the reply is sent with reply_connected or
+// reply_failed once the destination is connected
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    credentials: Option<&ProxyCredentials>,
+) -> io::Result<(String, u16)> {
+    let version = stream.read_u8().await?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported SOCKS version {}",
+            version
+        )));
+    }
+    let mut methods = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut methods).await?;
+    let method = match credentials {
+        Some(_) => METHOD_PASSWORD,
+        None => METHOD_NO_AUTH,
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, METHOD_UNACCEPTABLE]).await?;
+        return Err(invalid_data(String::from(
+            "client offered no acceptable authentication method",
+        )));
+    }
+    stream.write_all(&[VERSION, method]).await?;
+    if let Some(credentials) = credentials {
+        authenticate(stream, credentials).await?;
+    }
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let [version, command, _, address_type] = request;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported SOCKS version {}",
+            version
+        )));
+    }
+    let host = match address_type {
+        ADDRESS_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ADDRESS_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        ADDRESS_DOMAIN => {
+            let mut domain = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain).map_err(|_| invalid_data(String::from("invalid domain")))?
+        }
+        _ => {
+            reply(stream, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(invalid_data(format!(
+                "unsupported address type {}",
+                address_type
+            )));
+        }
+    };
+    let port = stream.read_u16().await?;
+    if command != COMMAND_CONNECT {
+        reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(invalid_data(format!("unsupported command {}", command)));
+    }
+    Ok((host, port))
+}
+
+pub async fn reply_connected<S: AsyncWrite + Unpin>(stream: &mut S) -> io::Result<()> {
+    reply(stream, REPLY_SUCCEEDED).await
+}
+
+pub async fn reply_failed<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    error: &UpstreamError,
+) -> io::Result<()> {
+    reply(stream, reply_code(error)).await
+}
+
+fn reply_code(error: &UpstreamError) -> u8 {
+    let kind = match error {
+        UpstreamError::Dns { .. } => return REPLY_HOST_UNREACHABLE,
+        UpstreamError::Timeout { .. } => return REPLY_TTL_EXPIRED,
+        UpstreamError::Connect { source, .. } | UpstreamError::Proxy { source, .. } => {
+            source.kind()
+        }
+        _ => return REPLY_GENERAL_FAILURE,
+    };
+    match kind {
+        io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        io::ErrorKind::TimedOut => REPLY_TTL_EXPIRED,
+        io::ErrorKind::HostUnreachable => REPLY_HOST_UNREACHABLE,
+        io::ErrorKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    credentials: &ProxyCredentials,
+) -> io::Result<()> {
+    let version = stream.read_u8().await?;
+    if version != AUTH_VERSION {
+        return Err(invalid_data(format!(
+            "unsupported authentication version {}",
+            version
+        )));
+    }
+    let mut user = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut user).await?;
+    let mut password = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut password).await?;
+    if !credentials.matches(&user, &password) {
+        stream.write_all(&[AUTH_VERSION, 0x01]).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "invalid credentials for user {:?}",
+                String::from_utf8_lossy(&user)
+            ),
+        ));
+    }
+    stream.write_all(&[AUTH_VERSION, 0x00]).await
+}
+
+// The bound address is not meaningful since streams may be served without the connection
+async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, code: u8) -> io::Result<()> {
+    stream
+        .write_all(&[VERSION, code, 0x00, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::duplex;
+
+    // Run the handshake against the client bytes, returning its result and the server replies
+    async fn run(
+        client: &[u8],
+        credentials: Option<&ProxyCredentials>,
+    ) -> (io::Result<(String, u16)>, Vec<u8>) {
+        let (mut server, mut peer) = duplex(1024);
+        peer.write_all(client).await.unwrap();
+        let result = handshake(&mut server, credentials).await;
+        drop(server);
+        let mut replies = Vec::new();
+        peer.read_to_end(&mut replies).await.unwrap();
+        (result, replies)
+    }
+
+    fn credentials() -> ProxyCredentials {
+        "user:secret".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_domain_destinations() {
+        let mut client = vec![5, 1, 0, 5, 1, 0, 3, 11];
+        client.extend_from_slice(b"example.com");
+        client.extend_from_slice(&443u16.to_be_bytes());
+        let (result, replies) = run(&client, None).await;
+        assert_eq!(result.unwrap(), ("example.com".to_string(), 443));
+        // The connection reply is left to the caller
+        assert_eq!(replies, [5, 0]);
+    }
+
+    #[tokio::test]
+    async fn accepts_address_destinations() {
+        let client = [5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 80];
+        let (result, _) = run(&client, None).await;
+        assert_eq!(result.unwrap(), ("10.0.0.1".to_string(), 80));
+
+        let mut client = vec![5, 1, 0, 5, 1, 0, 4];
+        client.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        client.extend_from_slice(&8080u16.to_be_bytes());
+        let (result, _) = run(&client, None).await;
+        assert_eq!(result.unwrap(), ("::1".to_string(), 8080));
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_password() {
+        let mut client = vec![5, 2, 0, 2, 1, 4];
+        client.extend_from_slice(b"user");
+        client.push(6);
+        client.extend_from_slice(b"secret");
+        client.extend_from_slice(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 22]);
+        let (result, replies) = run(&client, Some(&credentials())).await;
+        assert_eq!(result.unwrap(), ("127.0.0.1".to_string(), 22));
+        assert_eq!(replies, [5, 2, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_password() {
+        let mut client = vec![5, 1, 2, 1, 4];
+        client.extend_from_slice(b"user");
+        client.push(5);
+        client.extend_from_slice(b"wrong");
+        let (result, replies) = run(&client, Some(&credentials())).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(replies, [5, 2, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn rejects_clients_without_password_method() {
+        let (result, replies) = run(&[5, 1, 0], Some(&credentials())).await;
+        assert!(result.is_err());
+        assert_eq!(replies, [5, METHOD_UNACCEPTABLE]);
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_commands() {
+        // BIND
+        let client = [5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1, 0, 80];
+        let (result, replies) = run(&client, None).await;
+        assert!(result.is_err());
+        assert_eq!(replies[..4], [5, 0, 5, REPLY_COMMAND_NOT_SUPPORTED]);
+    }
+
+    #[test]
+    fn limits_credentials_length() {
+        assert!(parse_credentials("user:secret").is_ok());
+        assert!(parse_credentials(&format!("user:{}", "a".repeat(256))).is_err());
+        assert!(parse_credentials(":secret").is_err());
+    }
+
+    #[test]
+    fn maps_upstream_errors_to_replies() {
+        let connect = |kind| UpstreamError::Connect {
+            target: "example.com:80".to_string(),
+            source: io::Error::from(kind),
+        };
+        assert_eq!(
+            reply_code(&UpstreamError::Dns {
+                host: "example.com".to_string(),
+                source: io::Error::from(io::ErrorKind::NotFound),
+            }),
+            REPLY_HOST_UNREACHABLE
+        );
+        assert_eq!(
+            reply_code(&connect(io::ErrorKind::ConnectionRefused)),
+            REPLY_CONNECTION_REFUSED
+        );
+        assert_eq!(
+            reply_code(&UpstreamError::Timeout {
+                stage: "Connection",
+                target: "example.com:80".to_string(),
+                after: Duration::from_secs(1),
+            }),
+            REPLY_TTL_EXPIRED
+        );
+        assert_eq!(
+            reply_code(&connect(io::ErrorKind::Other)),
+            REPLY_GENERAL_FAILURE
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+// Limits for each stage of the pipeline, None disables the limit
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    // TCP connection to the upstream, including name resolution and the upstream proxy
+    pub connect: Option<Duration>,
+    // TLS handshakes with clients and upstreams
+    pub tls_handshake: Option<Duration>,
+    // Head of a client request, also closes HTTP/1 keep-alive connections waiting for one
+    pub header_read: Option<Duration>,
+    // Upstream response head after the request is sent
+    pub response: Option<Duration>,
+    // Client connections (including tunnels and WebSockets) without any traffic
+    pub idle: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Some(Duration::from_secs(10)),
+            tls_handshake: Some(Duration::from_secs(10)),
+            header_read: Some(Duration::from_secs(30)),
+            // Long polls and slow reports may take any time, so the limit is opt-in
+            response: None,
+            idle: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+// Run the future within the limit, None if it did not finish in time
+pub async fn within<F: Future>(limit: Option<Duration>, future: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+// Why the client connection was closed for a timeout, shared with the exchanges made on it
+#[derive(Clone, Debug, Default)]
+pub struct ClientTimeout(Arc<OnceLock<String>>);
+
+impl ClientTimeout {
+    pub fn set(&self, reason: String) {
+        let _ = self.0.set(reason);
+    }
+
+    pub fn reason(&self) -> Option<String> {
+        self.0.get().cloned()
+    }
+}
+
+// Stream failing with TimedOut once nothing is read or written for the timeout
+pub struct IdleStream<T> {
+    inner: T,
+    timeout: Option<Duration>,
+    deadline: Pin<Box<Sleep>>,
+    timed_out: ClientTimeout,
+}
+
+impl<T> IdleStream<T> {
+    pub fn new(inner: T, timeout: Option<Duration>) -> Self {
+        // Never fires when there is no timeout
+        let deadline = Box::pin(sleep(timeout.unwrap_or(Duration::from_secs(86400 * 365))));
+        IdleStream {
+            inner,
+            timeout,
+            deadline,
+            timed_out: ClientTimeout::default(),
+        }
+    }
+
+    // Set once the stream times out
+    pub fn timed_out(&self) -> ClientTimeout {
+        self.timed_out.clone()
+    }
+
+    fn touch(&mut self) {
+        if let Some(timeout) = self.timeout {
+            self.deadline.as_mut().reset(Instant::now() + timeout);
+        }
+    }
+
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        match self.timeout {
+            Some(timeout) if self.deadline.as_mut().poll(cx).is_ready() => {
+                warn!("Closing client connection idle for {:?}", timeout);
+                self.timed_out
+                    .set(format!("client connection was idle for {:?}", timeout));
+                Poll::Ready(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection is idle for too long",
+                ))
+            }
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.touch();
+                Poll::Ready(result)
+            }
+            Poll::Pending => this.poll_idle(cx).map(Err),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(result) => {
+                this.touch();
+                Poll::Ready(result)
+            }
+            Poll::Pending => this.poll_idle(cx).map(Err),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn idle_stream_records_timeout() {
+        let (client, _server) = duplex(64);
+        let mut stream = IdleStream::new(client, Some(Duration::from_millis(10)));
+        let timed_out = stream.timed_out();
+        let mut buf = [0u8; 8];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(timed_out.reason().unwrap().contains("idle"));
+    }
+
+    #[tokio::test]
+    async fn active_stream_has_no_timeout() {
+        let (client, mut server) = duplex(64);
+        let mut stream = IdleStream::new(client, Some(Duration::from_secs(5)));
+        server.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(stream.timed_out().reason().is_none());
+    }
+
+    #[tokio::test]
+    async fn within_limits_only_when_set() {
+        assert_eq!(within(None, async { 1 }).await, Some(1));
+        let slow = tokio::time::sleep(Duration::from_secs(5));
+        assert!(within(Some(Duration::from_millis(10)), slow)
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,37 @@
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::TcpStream;
+
+// Destination the connection was addressed to before iptables redirected it to the proxy
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn original_destination(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let socket = socket2::SockRef::from(stream);
+    let local_addr = stream.local_addr()?;
+    let destination = match local_addr {
+        SocketAddr::V4(_) => socket.original_dst_v4()?,
+        SocketAddr::V6(_) => socket.original_dst_v6()?,
+    };
+    let destination = destination.as_socket().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "original destination is not an ip address",
+        )
+    })?;
+    // Connections made to the listener directly would be relayed back to it forever
+    if destination == local_addr {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "connection was not redirected to the proxy",
+        ));
+    }
+    Ok(destination)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn original_destination(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent mode is supported only on linux",
+    ))
+}
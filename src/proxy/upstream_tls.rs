@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::host_pattern::HostPattern;
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{ResolvesClientCert, WebPkiServerVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+const PIN_PREFIX: &str = "sha256/";
+
+// Verification settings for TLS connections to upstream servers.
+// Written as comma separated options: "insecure", "roots:<pem file>", "pin:sha256/<base64>"
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UpstreamTlsPolicy {
+    // Accept any certificate, for test environments only
+    pub insecure: bool,
+    // PEM files with roots trusted in addition to the mozilla ones
+    pub extra_roots: Vec<String>,
+    // Accepted hashes of the server certificate public key, checked on top of the chain
+    pub pins: Vec<String>,
+}
+
+impl FromStr for UpstreamTlsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = UpstreamTlsPolicy::default();
+        for option in s.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            if option == "insecure" {
+                policy.insecure = true;
+            } else if let Some(path) = option.strip_prefix("roots:") {
+                policy.extra_roots.push(path.to_string());
+            } else if let Some(pin) = option.strip_prefix("pin:") {
+                decode_pin(pin)?;
+                policy.pins.push(pin.to_string());
+            } else {
+                return Err(format!("unknown upstream tls option {:?}", option));
+            }
+        }
+        Ok(policy)
+    }
+}
+
+// PEM files of a certificate chain and its key presented to upstreams requiring mTLS
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCertificate {
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamTlsSettings {
+    pub default: UpstreamTlsPolicy,
+    // The first matching host policy replaces the default one
+    pub hosts: Vec<(HostPattern, UpstreamTlsPolicy)>,
+    // The first matching client certificate is used, no client auth otherwise
+    pub client_certs: Vec<(HostPattern, ClientCertificate)>,
+}
+
+impl UpstreamTlsSettings {
+    // Parse per-host policies written as "<host pattern>=<policy>;<host pattern>=<policy>"
+    pub fn parse_hosts(s: &str) -> Result<Vec<(HostPattern, UpstreamTlsPolicy)>, String> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (pattern, policy) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected <host>=<policy>, got {:?}", entry))?;
+                Ok((pattern.parse()?, policy.parse()?))
+            })
+            .collect()
+    }
+
+    // Parse client certificates written as "<host pattern>=<cert file>,<key file>;..."
+    pub fn parse_client_certs(s: &str) -> Result<Vec<(HostPattern, ClientCertificate)>, String> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (pattern, files) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected <host>=<cert>,<key>, got {:?}", entry))?;
+                let (cert, key) = files
+                    .split_once(',')
+                    .ok_or_else(|| format!("expected <cert>,<key>, got {:?}", files))?;
+                Ok((
+                    pattern.parse()?,
+                    ClientCertificate {
+                        cert: cert.trim().to_string(),
+                        key: key.trim().to_string(),
+                    },
+                ))
+            })
+            .collect()
+    }
+}
+
+// Client configs built from the settings, shared by all upstream connections
+#[derive(Debug)]
+pub struct UpstreamTls {
+    default: Arc<ClientConfig>,
+    hosts: Vec<(HostPattern, Arc<ClientConfig>)>,
+    client_certs: Vec<HostPattern>,
+    // Configs presenting a client certificate, by (host policy, client certificate) indexes
+    authenticated: HashMap<(Option<usize>, usize), Arc<ClientConfig>>,
+}
+
+impl UpstreamTls {
+    pub fn new(settings: &UpstreamTlsSettings) -> Result<Self, UpstreamTlsError> {
+        let mut hosts = Vec::new();
+        for (pattern, policy) in settings.hosts.iter() {
+            hosts.push((pattern.clone(), Arc::new(build_client_config(policy)?)));
+        }
+        let default = Arc::new(build_client_config(&settings.default)?);
+
+        let mut authenticated = HashMap::new();
+        for (cert_idx, (_, client_cert)) in settings.client_certs.iter().enumerate() {
+            let certified_key = Arc::new(load_client_cert(client_cert, &default)?);
+            let policies = std::iter::once((None, &default))
+                .chain(hosts.iter().enumerate().map(|(i, (_, c))| (Some(i), c)));
+            for (policy_idx, config) in policies {
+                let mut config = ClientConfig::clone(config);
+                config.client_auth_cert_resolver =
+                    Arc::new(StaticClientCert(certified_key.clone()));
+                authenticated.insert((policy_idx, cert_idx), Arc::new(config));
+            }
+        }
+
+        Ok(UpstreamTls {
+            default,
+            hosts,
+            client_certs: settings
+                .client_certs
+                .iter()
+                .map(|(pattern, _)| pattern.clone())
+                .collect(),
+            authenticated,
+        })
+    }
+
+    // Set the protocols offered with ALPN on every client config
+    pub fn with_alpn(mut self, protocols: &[&[u8]]) -> Self {
+        let configs = std::iter::once(&mut self.default)
+            .chain(self.hosts.iter_mut().map(|(_, config)| config))
+            .chain(self.authenticated.values_mut());
+        for config in configs {
+            Arc::make_mut(config).alpn_protocols =
+                protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        }
+        self
+    }
+
+    pub fn config_for(&self, host: &str) -> Arc<ClientConfig> {
+        let policy_idx = self
+            .hosts
+            .iter()
+            .position(|(pattern, _)| pattern.matches(host));
+        let cert_idx = self
+            .client_certs
+            .iter()
+            .position(|pattern| pattern.matches(host));
+        match (policy_idx, cert_idx) {
+            (_, Some(cert_idx)) => self.authenticated[&(policy_idx, cert_idx)].clone(),
+            (Some(policy_idx), None) => self.hosts[policy_idx].1.clone(),
+            (None, None) => self.default.clone(),
+        }
+    }
+}
+
+impl Default for UpstreamTls {
+    fn default() -> Self {
+        UpstreamTls {
+            // Safe unwrap since the default policy does not read any files
+            default: Arc::new(build_client_config(&UpstreamTlsPolicy::default()).unwrap()),
+            hosts: Vec::new(),
+            client_certs: Vec::new(),
+            authenticated: HashMap::new(),
+        }
+    }
+}
+
+fn load_client_cert(
+    client_cert: &ClientCertificate,
+    config: &ClientConfig,
+) -> Result<CertifiedKey, UpstreamTlsError> {
+    let invalid = |e: String| UpstreamTlsError::InvalidClientCert(client_cert.cert.clone(), e);
+    let chain = CertificateDer::pem_file_iter(&client_cert.cert)
+        .map_err(|e| invalid(e.to_string()))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(e.to_string()))?;
+    let key = PrivateKeyDer::from_pem_file(&client_cert.key).map_err(|e| invalid(e.to_string()))?;
+    let signing_key = config
+        .crypto_provider()
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| invalid(e.to_string()))?;
+    Ok(CertifiedKey::new(chain, signing_key))
+}
+
+// Presents the same certificate to every server asking for one
+#[derive(Debug)]
+struct StaticClientCert(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for StaticClientCert {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+fn build_client_config(policy: &UpstreamTlsPolicy) -> Result<ClientConfig, UpstreamTlsError> {
+    let mut roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    for path in policy.extra_roots.iter() {
+        let certs = CertificateDer::pem_file_iter(path)
+            .map_err(|e| UpstreamTlsError::InvalidRoots(path.clone(), e.to_string()))?;
+        for cert in certs {
+            let cert =
+                cert.map_err(|e| UpstreamTlsError::InvalidRoots(path.clone(), e.to_string()))?;
+            roots
+                .add(cert)
+                .map_err(|e| UpstreamTlsError::InvalidRoots(path.clone(), e.to_string()))?;
+        }
+    }
+
+    let builder = ClientConfig::builder();
+    if !policy.insecure && policy.pins.is_empty() {
+        return Ok(builder.with_root_certificates(roots).with_no_client_auth());
+    }
+
+    let provider = builder.crypto_provider().clone();
+    let verifier = PolicyVerifier {
+        webpki: WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .build()?,
+        insecure: policy.insecure,
+        pins: policy
+            .pins
+            .iter()
+            .map(|pin| decode_pin(pin))
+            .collect::<Result<_, _>>()
+            .map_err(UpstreamTlsError::InvalidPin)?,
+        provider,
+    };
+    Ok(builder
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+fn decode_pin(pin: &str) -> Result<Vec<u8>, String> {
+    let encoded = pin
+        .strip_prefix(PIN_PREFIX)
+        .ok_or_else(|| format!("pin {:?} must start with {}", pin, PIN_PREFIX))?;
+    let hash = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| format!("pin {:?} is not valid base64: {}", pin, e))?;
+    if hash.len() != Sha256::output_size() {
+        return Err(format!("pin {:?} is not a sha256 hash", pin));
+    }
+    Ok(hash)
+}
+
+// Hash of the subject public key info, the same as used for HPKP pins
+fn spki_hash(cert: &CertificateDer<'_>) -> Result<Vec<u8>, rustls::Error> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|_| rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding))?;
+    Ok(Sha256::digest(parsed.tbs_certificate.subject_pki.raw).to_vec())
+}
+
+// Verifier allowing to skip chain validation and to check pinned keys
+#[derive(Debug)]
+struct PolicyVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    insecure: bool,
+    pins: Vec<Vec<u8>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PolicyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if !self.insecure {
+            self.webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if !self.pins.is_empty() && !self.pins.contains(&spki_hash(end_entity)?) {
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum UpstreamTlsError {
+    #[error("failed to load trusted roots from {0:?}: {1}")]
+    InvalidRoots(String, String),
+
+    #[error("invalid certificate pin: {0}")]
+    InvalidPin(String),
+
+    #[error("failed to load client certificate {0:?}: {1}")]
+    InvalidClientCert(String, String),
+
+    #[error("failed to build certificate verifier: {0}")]
+    Verifier(#[from] rustls::client::VerifierBuilderError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_policies() {
+        let pin = format!(
+            "sha256/{}",
+            base64::engine::general_purpose::STANDARD.encode([7u8; 32])
+        );
+        let policy: UpstreamTlsPolicy = format!("insecure, roots:/certs/corp.pem, pin:{}", pin)
+            .parse()
+            .unwrap();
+        assert!(policy.insecure);
+        assert_eq!(policy.extra_roots, vec!["/certs/corp.pem".to_string()]);
+        assert_eq!(policy.pins, vec![pin]);
+        assert!("pin:sha256/short".parse::<UpstreamTlsPolicy>().is_err());
+        assert!("strict".parse::<UpstreamTlsPolicy>().is_err());
+    }
+
+    #[test]
+    fn parses_client_certificates() {
+        let certs = UpstreamTlsSettings::parse_client_certs(
+            "*.internal.test=/certs/client.crt, /certs/client.key;api.test=a.pem,b.pem",
+        )
+        .unwrap();
+        assert_eq!(certs.len(), 2);
+        assert_eq!(certs[0].0, "*.internal.test".parse().unwrap());
+        assert_eq!(
+            certs[0].1,
+            ClientCertificate {
+                cert: "/certs/client.crt".to_string(),
+                key: "/certs/client.key".to_string(),
+            }
+        );
+        assert_eq!(certs[1].1.key, "b.pem");
+        assert!(UpstreamTlsSettings::parse_client_certs("api.test=a.pem").is_err());
+        assert!(UpstreamTlsSettings::parse_client_certs("a.pem,b.pem").is_err());
+    }
+}
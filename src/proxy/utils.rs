@@ -1,6 +1,6 @@
-use http::HeaderName;
-use http::Request;
+use http::{header, request, HeaderMap, HeaderName, HeaderValue, Request};
 use hyper::Uri;
+use std::net::Ipv6Addr;
 use thiserror::Error;
 
 pub const HEADER_PROXY_CONNECTION: HeaderName = HeaderName::from_static("proxy-connection");
@@ -39,7 +39,7 @@ pub fn clean_request<T>(mut req: http::Request<T>) -> Request<T> {
     req
 }
 
-// Get host from request
+// Get host from request, HTTP/2 requests carry it in the uri authority instead of the header
 pub fn extract_host<T>(req: &http::Request<T>) -> Option<String> {
     if let Some(addr) = req.headers().get(http::header::HOST) {
         let addr = addr.to_str();
@@ -48,25 +48,135 @@ pub fn extract_host<T>(req: &http::Request<T>) -> Option<String> {
         }
     }
 
-    if let Some(host) = req.uri().host() {
-        return Some(String::from(host));
+    if let Some(authority) = req.uri().authority() {
+        return Some(String::from(authority.as_str()));
     }
 
-    return None;
+    None
 }
 
-// Parse host header
-pub fn parse_host_header(host: &String, fallback_port: u16) -> Result<(String, u16), String> {
-    match host.find(":") {
-        Some(idx) => {
-            if idx == host.len() - 1 {
-                return Err(String::from("unexpected eol while parsing port"));
+// Parse host header, IPv6 addresses are bracketed there and returned without brackets
+pub fn parse_host_header(host: &str, fallback_port: u16) -> Result<(String, u16), String> {
+    let (name, port) = match host.strip_prefix('[') {
+        Some(rest) => {
+            let (address, port) = rest
+                .split_once(']')
+                .ok_or_else(|| String::from("unclosed bracket in host"))?;
+            if address.parse::<Ipv6Addr>().is_err() {
+                return Err(String::from("invalid IPv6 address"));
             }
-            if let Ok(port) = host[(idx + 1)..].parse::<u16>() {
-                return Ok((String::from(&host[..idx]), port));
+            match port {
+                "" => (address, None),
+                port => (
+                    address,
+                    Some(
+                        port.strip_prefix(':')
+                            .ok_or_else(|| String::from("invalid host"))?,
+                    ),
+                ),
             }
-            Err(String::from("invalid host"))
         }
-        None => Ok((host.clone(), fallback_port)),
+        None => match host.split_once(':') {
+            Some((name, port)) => (name, Some(port)),
+            None => (host, None),
+        },
+    };
+    let port = match port {
+        None => fallback_port,
+        Some("") => return Err(String::from("unexpected eol while parsing port")),
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| String::from("invalid host"))?,
+    };
+    Ok((name.to_string(), port))
+}
+
+// Host name the request is addressed to, without the port
+pub fn request_host(parts: &request::Parts) -> Option<String> {
+    if let Some(host) = parts.uri.host() {
+        return Some(
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+        );
+    }
+    let host = parts.headers.get(header::HOST)?.to_str().ok()?;
+    parse_host_header(host, 0).ok().map(|(host, _)| host)
+}
+
+// Bodies replaced by the proxy have known lengths, so they are not sent chunked
+pub fn set_content_length(headers: &mut HeaderMap, len: usize) {
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+}
+
+// Request head shared by the proxy module tests
+#[cfg(test)]
+pub(crate) fn test_request(method: http::Method, uri: &str) -> request::Parts {
+    let (parts, ()) = Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(())
+        .unwrap()
+        .into_parts();
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(host: &str) -> Result<(String, u16), String> {
+        parse_host_header(host, 443)
+    }
+
+    #[test]
+    fn parses_host_and_port() {
+        assert_eq!(parsed("example.com"), Ok(("example.com".to_string(), 443)));
+        assert_eq!(
+            parsed("example.com:8443"),
+            Ok(("example.com".to_string(), 8443))
+        );
+        assert_eq!(parsed("127.0.0.1:80"), Ok(("127.0.0.1".to_string(), 80)));
+    }
+
+    #[test]
+    fn parses_ipv6_literals() {
+        assert_eq!(parsed("[::1]:8443"), Ok(("::1".to_string(), 8443)));
+        assert_eq!(
+            parsed("[2001:db8::1]"),
+            Ok(("2001:db8::1".to_string(), 443))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_hosts() {
+        for host in [
+            "example.com:",
+            "example.com:port",
+            "example.com:70000",
+            "::1",
+            "[::1",
+            "[::1]8443",
+            "[::1]:",
+            "[example.com]:443",
+        ] {
+            assert!(parsed(host).is_err(), "{:?} is accepted", host);
+        }
+    }
+
+    #[test]
+    fn request_host_has_no_brackets() {
+        let (parts, ()) = Request::get("http://[::1]:8080/")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert_eq!(request_host(&parts).as_deref(), Some("::1"));
+        let (parts, ()) = Request::get("/")
+            .header(header::HOST, "[::1]:8080")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert_eq!(request_host(&parts).as_deref(), Some("::1"));
     }
 }
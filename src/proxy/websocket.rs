@@ -0,0 +1,327 @@
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use bytes::{Buf, Bytes, BytesMut};
+use http::{header, Request};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use log::{debug, error};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub type FrameCallbackType = Arc<Mutex<dyn Fn(Frame) + Send + 'static>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+// A frame seen on a relayed WebSocket connection
+#[derive(Clone, Debug)]
+pub struct Frame {
+    // Id of the handshake exchange
+    pub exchange_id: String,
+    pub direction: Direction,
+    pub fin: bool,
+    pub opcode: u8,
+    // Unmasked payload, cut at the capture limit
+    pub payload: Bytes,
+    pub truncated: bool,
+    pub timestamp: SystemTime,
+}
+
+pub fn is_upgrade_request<T>(req: &Request<T>) -> bool {
+    let has_token = |name, token: &str| {
+        req.headers().get_all(name).iter().any(|value| {
+            value
+                .to_str()
+                .unwrap_or("")
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    has_token(header::CONNECTION, "upgrade") && has_token(header::UPGRADE, "websocket")
+}
+
+// Copy bytes between both upgraded connections until they are closed, reporting parsed frames
+pub async fn relay(
+    client: OnUpgrade,
+    upstream: OnUpgrade,
+    exchange_id: String,
+    callback: Option<FrameCallbackType>,
+    capture_limit: usize,
+) {
+    let (client, upstream) = match tokio::try_join!(client, upstream) {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            error!("WebSocket upgrade error: {}", e);
+            return;
+        }
+    };
+    debug!("Relaying WebSocket connection {}", exchange_id);
+    let (client_read, client_write) = tokio::io::split(TokioIo::new(client));
+    let (upstream_read, upstream_write) = tokio::io::split(TokioIo::new(upstream));
+
+    let report = |direction| {
+        let exchange_id = exchange_id.clone();
+        let callback = callback.clone();
+        move |fin, opcode, payload, truncated| {
+            let Some(callback) = &callback else {
+                return;
+            };
+            let frame = Frame {
+                exchange_id: exchange_id.clone(),
+                direction,
+                fin,
+                opcode,
+                payload,
+                truncated,
+                timestamp: SystemTime::now(),
+            };
+            match callback.lock() {
+                Ok(callback) => callback(frame),
+                Err(_) => error!("failed to use frame callback: the mutex is poisoned"),
+            }
+        }
+    };
+    tokio::join!(
+        pump(
+            client_read,
+            upstream_write,
+            FrameParser::new(capture_limit),
+            report(Direction::ClientToServer),
+        ),
+        pump(
+            upstream_read,
+            client_write,
+            FrameParser::new(capture_limit),
+            report(Direction::ServerToClient),
+        ),
+    );
+    debug!("WebSocket connection {} is closed", exchange_id);
+}
+
+async fn pump<R, W, F>(mut from: R, mut to: W, mut parser: FrameParser, mut report: F)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnMut(bool, u8, Bytes, bool),
+{
+    let mut buf = vec![0u8; 16 * 1024];
+    loop {
+        let n = match from.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if to.write_all(&buf[..n]).await.is_err() || to.flush().await.is_err() {
+            break;
+        }
+        parser.feed(&buf[..n], &mut report);
+    }
+    let _ = to.shutdown().await;
+}
+
+struct PartialFrame {
+    fin: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    remaining: u64,
+    offset: usize,
+    payload: BytesMut,
+    truncated: bool,
+}
+
+// Incremental parser of frames (RFC 6455, section 5.2), the bytes are only observed
+struct FrameParser {
+    limit: usize,
+    pending: BytesMut,
+    current: Option<PartialFrame>,
+}
+
+impl FrameParser {
+    fn new(limit: usize) -> Self {
+        FrameParser {
+            limit,
+            pending: BytesMut::new(),
+            current: None,
+        }
+    }
+
+    fn feed<F: FnMut(bool, u8, Bytes, bool)>(&mut self, data: &[u8], report: &mut F) {
+        self.pending.extend_from_slice(data);
+        loop {
+            if self.current.is_none() {
+                match self.parse_header() {
+                    Some(frame) => self.current = Some(frame),
+                    None => return,
+                }
+            }
+            // Safe unwrap since the frame is set above
+            let frame = self.current.as_mut().unwrap();
+            let n = (frame.remaining.min(self.pending.len() as u64)) as usize;
+            let mut chunk = self.pending.split_to(n);
+            if let Some(mask) = frame.mask {
+                for (i, byte) in chunk.iter_mut().enumerate() {
+                    *byte ^= mask[(frame.offset + i) % 4];
+                }
+            }
+            let left = self.limit.saturating_sub(frame.payload.len());
+            frame.truncated |= chunk.len() > left;
+            frame
+                .payload
+                .extend_from_slice(&chunk[..chunk.len().min(left)]);
+            frame.offset += n;
+            frame.remaining -= n as u64;
+            if frame.remaining > 0 {
+                return;
+            }
+            // Safe unwrap since the frame is set above
+            let frame = self.current.take().unwrap();
+            report(
+                frame.fin,
+                frame.opcode,
+                frame.payload.freeze(),
+                frame.truncated,
+            );
+        }
+    }
+
+    fn parse_header(&mut self) -> Option<PartialFrame> {
+        let header = &self.pending[..];
+        if header.len() < 2 {
+            return None;
+        }
+        let masked = header[1] & 0x80 != 0;
+        let (len, mut size) = match header[1] & 0x7f {
+            126 if header.len() >= 4 => (u16::from_be_bytes([header[2], header[3]]) as u64, 4),
+            127 if header.len() >= 10 => {
+                // Safe unwrap since the slice is 8 bytes long
+                (u64::from_be_bytes(header[2..10].try_into().unwrap()), 10)
+            }
+            126 | 127 => return None,
+            len => (len as u64, 2),
+        };
+        let mask = if masked {
+            if header.len() < size + 4 {
+                return None;
+            }
+            size += 4;
+            // Safe unwrap since the slice is 4 bytes long
+            Some(header[size - 4..size].try_into().unwrap())
+        } else {
+            None
+        };
+        let frame = PartialFrame {
+            fin: header[0] & 0x80 != 0,
+            opcode: header[0] & 0x0f,
+            mask,
+            remaining: len,
+            offset: 0,
+            payload: BytesMut::new(),
+            truncated: false,
+        };
+        self.pending.advance(size);
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(limit: usize, chunks: &[&[u8]]) -> Vec<(bool, u8, Bytes, bool)> {
+        let mut parser = FrameParser::new(limit);
+        let mut frames = Vec::new();
+        for chunk in chunks {
+            parser.feed(chunk, &mut |fin, opcode, payload, truncated| {
+                frames.push((fin, opcode, payload, truncated))
+            });
+        }
+        frames
+    }
+
+    fn masked_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn unmasks_client_frames() {
+        let frames = parse(1024, &[&masked_frame(1, b"hello")]);
+        assert_eq!(frames, vec![(true, 1, Bytes::from("hello"), false)]);
+    }
+
+    #[test]
+    fn parses_frames_split_at_any_byte() {
+        let frame = masked_frame(2, b"split payload");
+        for at in 1..frame.len() {
+            let frames = parse(1024, &[&frame[..at], &frame[at..]]);
+            assert_eq!(frames, vec![(true, 2, Bytes::from("split payload"), false)]);
+        }
+    }
+
+    #[test]
+    fn parses_several_frames_in_one_chunk() {
+        let mut data = vec![0x01, 3];
+        data.extend_from_slice(b"abc");
+        data.extend_from_slice(&[0x80, 0]);
+        data.extend_from_slice(&[0x89, 1, b'x']);
+        let frames = parse(1024, &[&data]);
+        assert_eq!(
+            frames,
+            vec![
+                (false, 1, Bytes::from("abc"), false),
+                (true, 0, Bytes::new(), false),
+                (true, 9, Bytes::from("x"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_extended_lengths() {
+        let payload = vec![b'a'; 300];
+        let mut data = vec![0x82, 126, 1, 44];
+        data.extend_from_slice(&payload);
+        let frames = parse(1024, &[&data]);
+        assert_eq!(frames, vec![(true, 2, Bytes::from(payload.clone()), false)]);
+
+        let mut data = vec![0x82, 127, 0, 0, 0, 0, 0, 0, 1, 44];
+        data.extend_from_slice(&payload);
+        let frames = parse(1024, &[&data]);
+        assert_eq!(frames, vec![(true, 2, Bytes::from(payload), false)]);
+    }
+
+    #[test]
+    fn truncates_payload_at_the_limit() {
+        let mut data = vec![0x81, 10];
+        data.extend_from_slice(b"0123456789");
+        data.extend_from_slice(&[0x81, 2]);
+        data.extend_from_slice(b"ok");
+        let frames = parse(4, &[&data[..5], &data[5..]]);
+        assert_eq!(
+            frames,
+            vec![
+                (true, 1, Bytes::from("0123"), true),
+                (true, 1, Bytes::from("ok"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn detects_upgrade_requests() {
+        let req = Request::builder()
+            .header(header::CONNECTION, "keep-alive, Upgrade")
+            .header(header::UPGRADE, "WebSocket")
+            .body(())
+            .unwrap();
+        assert!(is_upgrade_request(&req));
+        let req = Request::builder()
+            .header(header::UPGRADE, "websocket")
+            .body(())
+            .unwrap();
+        assert!(!is_upgrade_request(&req));
+    }
+}
@@ -23,10 +23,21 @@ pub trait Scanner {
     fn scan_xss(&self, reqresp: Reqresp) -> DynFuture<Result<Vec<String>, ScannerError>>;
 }
 
-#[derive(Clone)]
-pub struct SimpleScanner {}
+#[derive(Clone, Default)]
+pub struct SimpleScanner {
+    client: Client,
+}
+
+impl SimpleScanner {
+    pub fn new(client: Client) -> Self {
+        SimpleScanner { client }
+    }
+}
 
-async fn resend_request_internal(req: Request) -> Result<http::Response<BodyType>, ScannerError> {
+async fn resend_request_internal(
+    client: &Client,
+    req: Request,
+) -> Result<http::Response<BodyType>, ScannerError> {
     let (req, is_https) = req.into();
     let full_host = utils::extract_host(&req).unwrap();
     let (host, port) = utils::parse_host_header(
@@ -37,7 +48,8 @@ async fn resend_request_internal(req: Request) -> Result<http::Response<BodyType
         },
     )
     .unwrap();
-    let resp = Client::send_request(req, host, port, is_https)
+    let resp = client
+        .send_request(req, host, port, is_https)
         .await
         .map_err(|_| ScannerError::RequestFailed)?;
     Ok(resp)
@@ -48,10 +60,12 @@ impl Scanner for SimpleScanner {
         &self,
         req: Request,
     ) -> DynFuture<Result<http::Response<BodyType>, ScannerError>> {
-        Box::pin(async move { resend_request_internal(req).await })
+        let client = self.client.clone();
+        Box::pin(async move { resend_request_internal(&client, req).await })
     }
 
     fn scan_xss(&self, reqresp: Reqresp) -> DynFuture<Result<Vec<String>, ScannerError>> {
+        let client = self.client.clone();
         Box::pin(async move {
             let req = reqresp.req;
             let mut result = Vec::new();
@@ -67,7 +81,7 @@ impl Scanner for SimpleScanner {
                     param_value.clear();
                     param_value.push_str(XSS_STRING);
                     debug!("Scanning with request: {:?}", req);
-                    let response_body = resend_request_internal(req)
+                    let response_body = resend_request_internal(&client, req)
                         .await?
                         .into_body()
                         .collect()
@@ -76,14 +90,14 @@ impl Scanner for SimpleScanner {
                         .to_bytes();
                     // Generally, the response is not a valid ascii, so
                     // we should scan with byte scanning
-                    if let Some(_) =
-                        response_body
-                            .to_vec()
-                            .windows(XSS_STRING.len())
-                            .position(|window| {
-                                window == XSS_STRING.as_bytes()
-                                    || window == ORIGINAL_XSS_STRING.as_bytes()
-                            })
+                    if response_body
+                        .to_vec()
+                        .windows(XSS_STRING.len())
+                        .position(|window| {
+                            window == XSS_STRING.as_bytes()
+                                || window == ORIGINAL_XSS_STRING.as_bytes()
+                        })
+                        .is_some()
                     {
                         result.push(key.clone());
                     }
@@ -99,7 +113,7 @@ impl Scanner for SimpleScanner {
                         param_value.clear();
                         param_value.push_str(ORIGINAL_XSS_STRING);
                         debug!("Scanning with request: {:?}", req);
-                        let response_body = resend_request_internal(req)
+                        let response_body = resend_request_internal(&client, req)
                             .await?
                             .into_body()
                             .collect()
@@ -107,14 +121,14 @@ impl Scanner for SimpleScanner {
                             .map_err(|_| ScannerError::BodyLoadFailed)?
                             .to_bytes();
                         // Same here
-                        if let Some(_) =
-                            response_body
-                                .to_vec()
-                                .windows(XSS_STRING.len())
-                                .position(|window| {
-                                    window == XSS_STRING.as_bytes()
-                                        || window == ORIGINAL_XSS_STRING.as_bytes()
-                                })
+                        if response_body
+                            .to_vec()
+                            .windows(XSS_STRING.len())
+                            .position(|window| {
+                                window == XSS_STRING.as_bytes()
+                                    || window == ORIGINAL_XSS_STRING.as_bytes()
+                            })
+                            .is_some()
                         {
                             result.push(key.clone());
                         }
@@ -1,2 +1,3 @@
 pub mod mongodb_storage;
+#[allow(clippy::module_inception)]
 pub mod storage;
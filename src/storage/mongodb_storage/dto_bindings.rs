@@ -11,24 +11,83 @@ pub(super) struct Reqresp {
     pub _id: String,
     pub req: dto::Request,
     pub resp: dto::Response,
+    #[serde(default)]
+    pub truncated: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 impl From<dto::Reqresp> for Reqresp {
     fn from(value: dto::Reqresp) -> Self {
-        return Reqresp {
+        Reqresp {
             _id: value.id,
             req: value.req,
             resp: value.resp,
-        };
+            truncated: value.truncated,
+            user: value.user,
+            error: value.error,
+        }
     }
 }
 
-impl Into<dto::Reqresp> for Reqresp {
-    fn into(self) -> dto::Reqresp {
+impl From<Reqresp> for dto::Reqresp {
+    fn from(val: Reqresp) -> Self {
         dto::Reqresp {
-            id: self._id,
-            req: self.req,
-            resp: self.resp,
+            id: val._id,
+            req: val.req,
+            resp: val.resp,
+            truncated: val.truncated,
+            user: val.user,
+            error: val.error,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct WebSocketFrame {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    #[serde(serialize_with = "serialize_hex_string_as_object_id")]
+    #[serde(deserialize_with = "deserialize_hex_string_from_object_id")]
+    pub _id: String,
+    #[serde(serialize_with = "serialize_hex_string_as_object_id")]
+    #[serde(deserialize_with = "deserialize_hex_string_from_object_id")]
+    pub reqresp_id: String,
+    pub direction: String,
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+    pub truncated: bool,
+    pub timestamp: u64,
+}
+
+impl From<dto::WebSocketFrame> for WebSocketFrame {
+    fn from(value: dto::WebSocketFrame) -> Self {
+        WebSocketFrame {
+            _id: value.id,
+            reqresp_id: value.reqresp_id,
+            direction: value.direction,
+            fin: value.fin,
+            opcode: value.opcode,
+            payload: value.payload,
+            truncated: value.truncated,
+            timestamp: value.timestamp,
+        }
+    }
+}
+
+impl From<WebSocketFrame> for dto::WebSocketFrame {
+    fn from(val: WebSocketFrame) -> Self {
+        dto::WebSocketFrame {
+            id: val._id,
+            reqresp_id: val.reqresp_id,
+            direction: val.direction,
+            fin: val.fin,
+            opcode: val.opcode,
+            payload: val.payload,
+            truncated: val.truncated,
+            timestamp: val.timestamp,
         }
     }
 }
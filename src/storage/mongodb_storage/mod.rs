@@ -1,4 +1,4 @@
-use crate::dto::Reqresp;
+use crate::dto::{Reqresp, WebSocketFrame};
 
 use super::storage::ReqrespStorage;
 use super::storage::StorageError;
@@ -11,6 +11,7 @@ mod dto_bindings;
 
 const DATABASE_NAME: &str = "rusty_proxy";
 const COLLECTION_NAME: &str = "reqresp";
+const FRAMES_COLLECTION_NAME: &str = "websocket_frame";
 
 #[derive(Clone)]
 pub struct MongoDbStorage {
@@ -31,7 +32,7 @@ impl ReqrespStorage for MongoDbStorage {
             reqresps
                 .insert_one(dto_bindings::Reqresp::from(r))
                 .await
-                .unwrap();
+                .map_err(|_| StorageError::Unknown)?;
             Ok(())
         })
     }
@@ -41,7 +42,7 @@ impl ReqrespStorage for MongoDbStorage {
         let reqresps: Collection<dto_bindings::Reqresp> = database.collection(COLLECTION_NAME);
         Box::pin(async move {
             let cursor = reqresps.find(doc! {}).await;
-            if let Err(_) = cursor {
+            if cursor.is_err() {
                 return Err(StorageError::Unknown);
             }
             let mut cursor = cursor.unwrap();
@@ -55,17 +56,56 @@ impl ReqrespStorage for MongoDbStorage {
         })
     }
 
-    fn get_reqresp_by_id(&self, id: &String) -> DynFuture<Result<Option<Reqresp>, StorageError>> {
+    fn get_reqresp_by_id(&self, id: &str) -> DynFuture<Result<Option<Reqresp>, StorageError>> {
         let database = self.client.database(DATABASE_NAME);
         let reqresps: Collection<dto_bindings::Reqresp> = database.collection(COLLECTION_NAME);
-        let id = id.clone();
+        let id = id.to_string();
         Box::pin(async move {
             let id = bson::oid::ObjectId::parse_str(id).map_err(|_| StorageError::Unknown)?;
             let reqresp = reqresps.find_one(doc! {"_id": id}).await;
-            if let Err(_) = reqresp {
+            if reqresp.is_err() {
                 return Err(StorageError::Unknown);
             }
             Ok(reqresp.unwrap().map(|r| r.into()))
         })
     }
+
+    fn add_websocket_frame(&self, f: WebSocketFrame) -> DynFuture<Result<(), StorageError>> {
+        let database = self.client.database(DATABASE_NAME);
+        let frames: Collection<dto_bindings::WebSocketFrame> =
+            database.collection(FRAMES_COLLECTION_NAME);
+        Box::pin(async move {
+            frames
+                .insert_one(dto_bindings::WebSocketFrame::from(f))
+                .await
+                .map_err(|_| StorageError::Unknown)?;
+            Ok(())
+        })
+    }
+
+    fn get_websocket_frames(
+        &self,
+        reqresp_id: &str,
+    ) -> DynFuture<Result<Vec<WebSocketFrame>, StorageError>> {
+        let database = self.client.database(DATABASE_NAME);
+        let frames: Collection<dto_bindings::WebSocketFrame> =
+            database.collection(FRAMES_COLLECTION_NAME);
+        let reqresp_id = reqresp_id.to_string();
+        Box::pin(async move {
+            let reqresp_id =
+                bson::oid::ObjectId::parse_str(reqresp_id).map_err(|_| StorageError::Unknown)?;
+            let mut cursor = frames
+                .find(doc! {"reqresp_id": reqresp_id})
+                .sort(doc! {"timestamp": 1, "_id": 1})
+                .await
+                .map_err(|_| StorageError::Unknown)?;
+            let mut result = Vec::new();
+            while let Some(frame_doc) =
+                cursor.try_next().await.map_err(|_| StorageError::Unknown)?
+            {
+                result.push(frame_doc.into());
+            }
+            Ok(result)
+        })
+    }
 }
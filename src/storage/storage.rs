@@ -1,4 +1,4 @@
-use crate::dto::Reqresp;
+use crate::dto::{Reqresp, WebSocketFrame};
 use thiserror::Error;
 
 use crate::DynFuture;
@@ -6,7 +6,12 @@ use crate::DynFuture;
 pub trait ReqrespStorage {
     fn add_reqresp(&self, r: Reqresp) -> DynFuture<Result<(), StorageError>>;
     fn get_reqresps(&self) -> DynFuture<Result<Vec<Reqresp>, StorageError>>;
-    fn get_reqresp_by_id(&self, id: &String) -> DynFuture<Result<Option<Reqresp>, StorageError>>;
+    fn get_reqresp_by_id(&self, id: &str) -> DynFuture<Result<Option<Reqresp>, StorageError>>;
+    fn add_websocket_frame(&self, f: WebSocketFrame) -> DynFuture<Result<(), StorageError>>;
+    fn get_websocket_frames(
+        &self,
+        reqresp_id: &str,
+    ) -> DynFuture<Result<Vec<WebSocketFrame>, StorageError>>;
 }
 
 #[derive(Error, Debug)]